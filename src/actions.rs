@@ -1,21 +1,37 @@
+mod docker;
+mod email;
+mod notify;
+mod rsync;
+mod script;
 mod shell;
 mod ssh;
 mod webhook;
 
 use crate::envs::Envs;
 use crate::repo::Context;
-use anyhow;
+use schemars::JsonSchema;
 use serde::Deserialize;
 
+use docker::DockerAction;
+use email::EmailAction;
+use notify::NotifyAction;
+use rsync::RsyncAction;
+use script::ScriptAction;
 use shell::ShellAction;
 use ssh::SshAction;
 use webhook::WebHookAction;
 
 pub trait IAction {
     fn run(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()>;
+    /// Print what would be done with rendered env, without executing
+    fn plan(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()>;
+    fn name(&self) -> &str;
+    /// Env defined on the action
+    fn envs(&self) -> &Envs;
 }
 
-#[derive(Debug, Deserialize)]
+/// Action of pipeline, the kind is specified by `runner`
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(tag = "runner")]
 pub enum Action {
     #[serde(rename = "shell")]
@@ -26,11 +42,75 @@ pub enum Action {
     WebHook(WebHookAction),
     #[serde(rename = "ssh")]
     Ssh(SshAction),
+    #[serde(rename = "docker")]
+    Docker(DockerAction),
+    #[serde(rename = "script")]
+    Script(ScriptAction),
+    #[serde(rename = "rsync")]
+    Rsync(RsyncAction),
+    #[serde(rename = "notify")]
+    Notify(NotifyAction),
+    #[serde(rename = "email")]
+    Email(EmailAction),
+}
+
+impl Action {
+    /// Whether it notifies of pipeline status, which runs per `notify_on`
+    /// of pipeline by default
+    pub fn is_notification(&self) -> bool {
+        matches!(self, Action::Notify(_) | Action::Email(_))
+    }
+}
+
+/// When to run an action, by status of previous actions of the pipeline
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RunOn {
+    #[default]
+    Success,
+    Failure,
+    Always,
+}
+
+/// Action of pipeline, with condition to run on
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Step {
+    /// Run when previous actions succeeded, failed or always, e.g. to
+    /// notify on failure
+    on: Option<RunOn>,
+    #[serde(flatten)]
+    action: Action,
+}
+
+impl RunOn {
+    /// Whether to run, given any previous action failed or not
+    pub fn matches(&self, failed: bool) -> bool {
+        match self {
+            RunOn::Success => !failed,
+            RunOn::Failure => failed,
+            RunOn::Always => true,
+        }
+    }
+}
+
+impl Step {
+    pub fn action(&self) -> &Action {
+        &self.action
+    }
+
+    /// When to run, notifications default to `notify_on` of pipeline
+    pub fn run_on(&self, notify_on: Option<RunOn>) -> RunOn {
+        match self.on {
+            Some(on) => on,
+            None if self.action.is_notification() => notify_on.unwrap_or_default(),
+            None => RunOn::default(),
+        }
+    }
 }
 
 impl IAction for Action {
     fn run(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
-        println!("");
+        println!();
         match self {
             Action::Ssh(action) => action.run(ctx, parent_env),
             Action::Shell(action) => {
@@ -42,6 +122,58 @@ impl IAction for Action {
                 action.run(ctx, parent_env)
             }
             Action::WebHook(action) => action.run(ctx, parent_env),
+            Action::Docker(action) => action.run(ctx, parent_env),
+            Action::Script(action) => action.run(ctx, parent_env),
+            Action::Rsync(action) => action.run(ctx, parent_env),
+            Action::Notify(action) => action.run(ctx, parent_env),
+            Action::Email(action) => action.run(ctx, parent_env),
+        }
+    }
+
+    fn plan(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
+        println!();
+        match self {
+            Action::Ssh(action) => action.plan(ctx, parent_env),
+            Action::Shell(action) => {
+                let action = action.set_shell("sh".to_string());
+                action.plan(ctx, parent_env)
+            }
+            Action::Bash(action) => {
+                let action = action.set_shell("bash".to_string());
+                action.plan(ctx, parent_env)
+            }
+            Action::WebHook(action) => action.plan(ctx, parent_env),
+            Action::Docker(action) => action.plan(ctx, parent_env),
+            Action::Script(action) => action.plan(ctx, parent_env),
+            Action::Rsync(action) => action.plan(ctx, parent_env),
+            Action::Notify(action) => action.plan(ctx, parent_env),
+            Action::Email(action) => action.plan(ctx, parent_env),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Action::Ssh(action) => action.name(),
+            Action::Shell(action) | Action::Bash(action) => action.name(),
+            Action::WebHook(action) => action.name(),
+            Action::Docker(action) => action.name(),
+            Action::Script(action) => action.name(),
+            Action::Rsync(action) => action.name(),
+            Action::Notify(action) => action.name(),
+            Action::Email(action) => action.name(),
+        }
+    }
+
+    fn envs(&self) -> &Envs {
+        match self {
+            Action::Ssh(action) => action.envs(),
+            Action::Shell(action) | Action::Bash(action) => action.envs(),
+            Action::WebHook(action) => action.envs(),
+            Action::Docker(action) => action.envs(),
+            Action::Script(action) => action.envs(),
+            Action::Rsync(action) => action.envs(),
+            Action::Notify(action) => action.envs(),
+            Action::Email(action) => action.envs(),
         }
     }
 }
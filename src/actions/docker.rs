@@ -0,0 +1,246 @@
+use crate::actions::shell::stream_output;
+use crate::actions::IAction;
+use crate::envs::Envs;
+use crate::helper::print_vars;
+use crate::repo::Context;
+use anyhow::anyhow;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Run script in a container, with workspace mounted as working dir
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DockerAction {
+    name: String,
+    image: String,
+    script: String,
+    /// Container engine to run with
+    #[serde(default)]
+    engine: Engine,
+    /// Shell in the image to run script with
+    #[serde(default = "default_shell")]
+    shell: String,
+    /// User to run as in container, e.g. `1000:1000`
+    user: Option<String>,
+    /// Memory limit, e.g. `512m`
+    memory: Option<String>,
+    /// Number of CPUs, e.g. `1.5`
+    cpus: Option<String>,
+    /// Extra args for `docker run`
+    #[serde(default)]
+    args: Vec<String>,
+
+    #[serde(flatten)]
+    envs: Envs,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum Engine {
+    #[default]
+    Docker,
+    Podman,
+}
+
+impl Engine {
+    fn command(&self) -> &'static str {
+        match self {
+            Engine::Docker => "docker",
+            Engine::Podman => "podman",
+        }
+    }
+}
+
+fn default_shell() -> String {
+    "sh".to_string()
+}
+
+/// Where workspace is mounted in container
+const CONTAINER_WORKSPACE: &str = "/workspace";
+/// Where output env file is mounted in container
+const CONTAINER_OUTPUT_ENV: &str = "/run/arrow.env";
+
+/// Container to be removed upon drop, in case it is left over
+struct Container {
+    engine: Engine,
+    name: String,
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        let _ = Command::new(self.engine.command())
+            .arg("rm")
+            .arg("-f")
+            .arg(&self.name)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+impl IAction for DockerAction {
+    fn run(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
+        println!("### {}\n", self.name);
+        let mut vars = self.envs.inherit(parent_env).build_env()?;
+        let workdir = ctx.workdir().to_string_lossy().to_string();
+        let mut mounts = vec![format!("{}:{}", workdir, CONTAINER_WORKSPACE)];
+        vars.insert(
+            "ARROW_WORKSPACE".to_string(),
+            CONTAINER_WORKSPACE.to_string(),
+        );
+        // host repo is not reachable from container
+        vars.remove("GIT_DIR");
+        if let Some(output_env) = vars.get("ARROW_ENV").cloned() {
+            // ensure it is a file, otherwise it is mounted as dir
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&output_env)?;
+            mounts.push(format!("{}:{}", output_env, CONTAINER_OUTPUT_ENV));
+            vars.insert("ARROW_ENV".to_string(), CONTAINER_OUTPUT_ENV.to_string());
+        }
+        let container = Container {
+            engine: self.engine,
+            name: Self::container_name(),
+        };
+        let args = self.run_args(&container.name, &mounts, &vars);
+        println!("{} {}\n", self.engine.command(), args.join(" "));
+        let mut cmd = Command::new(self.engine.command());
+        // pass env by name only, so values are not exposed in args
+        cmd.args(&args).envs(&vars).arg(&self.script);
+        let status = stream_output(&mut cmd)?;
+        drop(container);
+        if !status.success() {
+            return Err(anyhow!(
+                "Container of {} exited with {}",
+                self.image,
+                status
+            ));
+        }
+        Ok(())
+    }
+
+    fn plan(&self, _ctx: &Context, _parent_env: &Envs) -> anyhow::Result<()> {
+        println!("### {}\n", self.name);
+        let vars = self.envs.plan_env()?;
+        print_vars(&vars);
+        let mounts = vec![format!("<workspace>:{}", CONTAINER_WORKSPACE)];
+        let args = self.run_args("<name>", &mounts, &vars);
+        println!("  $ {} {}", self.engine.command(), args.join(" "));
+        for line in self.script.lines() {
+            println!("    {}", line);
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn envs(&self) -> &Envs {
+        &self.envs
+    }
+}
+
+impl DockerAction {
+    /// Build args of `docker run`, up to the shell to run script with
+    fn run_args(
+        &self,
+        name: &str,
+        mounts: &[String],
+        vars: &HashMap<String, String>,
+    ) -> Vec<String> {
+        let mut args = vec!["run", "--rm", "--name", name, "-w", CONTAINER_WORKSPACE]
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<String>>();
+        for mount in mounts {
+            args.push("-v".to_string());
+            args.push(mount.clone());
+        }
+        let mut keys: Vec<&String> = vars.keys().collect();
+        keys.sort();
+        for key in keys {
+            args.push("-e".to_string());
+            args.push(key.clone());
+        }
+        if let Some(ref user) = self.user {
+            args.push("--user".to_string());
+            args.push(user.clone());
+        }
+        if let Some(ref memory) = self.memory {
+            args.push("--memory".to_string());
+            args.push(memory.clone());
+        }
+        if let Some(ref cpus) = self.cpus {
+            args.push("--cpus".to_string());
+            args.push(cpus.clone());
+        }
+        args.extend(self.args.clone());
+        args.push(self.image.clone());
+        args.push(self.shell.clone());
+        args.push("-c".to_string());
+        args
+    }
+
+    fn container_name() -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        format!("arrow-{}-{}", std::process::id(), nanos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn docker(yaml: &str) -> DockerAction {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn run_args_of_defaults() {
+        let action = docker("{name: build, image: 'rust:1', script: make}");
+        let vars = HashMap::from([
+            ("TOKEN".to_string(), "secret".to_string()),
+            ("BRANCH".to_string(), "main".to_string()),
+        ]);
+        let mounts = vec!["/work:/workspace".to_string()];
+        assert_eq!(
+            action.run_args("c1", &mounts, &vars).join(" "),
+            "run --rm --name c1 -w /workspace -v /work:/workspace \
+             -e BRANCH -e TOKEN rust:1 sh -c"
+        );
+        assert_eq!(action.engine.command(), "docker");
+    }
+
+    #[test]
+    fn run_args_of_options() {
+        let action = docker(
+            "{name: build, image: alpine, script: make, engine: podman, shell: bash, \
+             user: '1000:1000', memory: 512m, cpus: '1.5', args: [--network, none]}",
+        );
+        let mounts = vec![
+            "/work:/workspace".to_string(),
+            "/tmp/o.env:/run/arrow.env".to_string(),
+        ];
+        assert_eq!(
+            action.run_args("c1", &mounts, &HashMap::new()).join(" "),
+            "run --rm --name c1 -w /workspace -v /work:/workspace -v /tmp/o.env:/run/arrow.env \
+             --user 1000:1000 --memory 512m --cpus 1.5 --network none alpine bash -c"
+        );
+        assert_eq!(action.engine.command(), "podman");
+    }
+
+    #[test]
+    fn container_name_of_process() {
+        let name = DockerAction::container_name();
+        assert!(name.starts_with(&format!("arrow-{}-", std::process::id())));
+    }
+}
@@ -0,0 +1,449 @@
+use crate::actions::notify::{commit_lines, headline};
+use crate::actions::webhook::handlebars;
+use crate::actions::IAction;
+use crate::decode;
+use crate::envs::Envs;
+use crate::helper::print_vars;
+use crate::pipeline::{FAILED_ACTION_ENV, FAILED_OUTPUT_ENV};
+use crate::repo::{Commit, Context};
+use anyhow::{anyhow, Context as _};
+use handlebars::Handlebars;
+use lettre::message::header::ContentType;
+use lettre::message::{Mailbox, Message};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{SendmailTransport, SmtpTransport, Transport};
+use nix::unistd::gethostname;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Send mail of pipeline status, by SMTP or local sendmail
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct EmailAction {
+    name: String,
+    /// Recipients rendered with env, e.g. `{{COMMIT_AUTHOR_EMAIL}}`, a
+    /// value may list several separated by `,`, and empty ones are skipped
+    #[serde(deserialize_with = "decode::string_or_seq")]
+    #[schemars(schema_with = "decode::string_or_seq_schema")]
+    to: Vec<String>,
+    #[serde(default, deserialize_with = "decode::string_or_seq")]
+    #[schemars(schema_with = "decode::string_or_seq_schema")]
+    cc: Vec<String>,
+    /// Sender, by default `arrow@<hostname>`
+    from: Option<String>,
+    /// Subject template, by default status of pipeline
+    subject: Option<String>,
+    /// Body template, by default a summary of status, commits, authors
+    /// and tail of output of the failed action
+    body: Option<String>,
+    /// SMTP server to send by, otherwise by local sendmail
+    smtp: Option<SmtpOptions>,
+    /// Sendmail command, `sendmail` in PATH by default
+    sendmail: Option<String>,
+
+    #[serde(flatten)]
+    envs: Envs,
+}
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct SmtpOptions {
+    host: String,
+    /// By default 587 for starttls, 465 for tls, and 25 for none
+    port: Option<u16>,
+    #[serde(default)]
+    security: Security,
+    username: Option<String>,
+    password: Option<String>,
+    /// Seconds to wait for server
+    timeout: Option<u64>,
+}
+
+/// Connection security of SMTP
+#[derive(Debug, Deserialize, Clone, Copy, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum Security {
+    #[default]
+    Starttls,
+    Tls,
+    /// Plain connection, e.g. to local relay
+    None,
+}
+
+/// Mail rendered with env
+struct Mail {
+    from: String,
+    to: Vec<String>,
+    cc: Vec<String>,
+    subject: String,
+    body: String,
+}
+
+impl IAction for EmailAction {
+    fn run(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
+        println!("### {}\n", self.name);
+        let vars = self.envs.inherit(parent_env).build_env()?;
+        let mail = self.render(ctx, &vars)?;
+        println!("  To: {}", mail.to.join(", "));
+        println!("  Subject: {}", mail.subject);
+        let message = mail.message()?;
+        match self.smtp {
+            Some(ref smtp) => {
+                let smtp = smtp.render_env(&handlebars(), &vars)?;
+                println!("  via smtp {}", smtp.host);
+                smtp.transport()?
+                    .send(&message)
+                    .with_context(|| format!("Failed to send mail by {}", smtp.host))?;
+            }
+            None => {
+                let sendmail = match self.sendmail {
+                    Some(ref command) => SendmailTransport::new_with_command(command),
+                    None => SendmailTransport::new(),
+                };
+                println!("  via sendmail");
+                sendmail
+                    .send(&message)
+                    .context("Failed to send mail by sendmail")?;
+            }
+        }
+        println!("  sent");
+        Ok(())
+    }
+
+    fn plan(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
+        println!("### {}\n", self.name);
+        print_vars(&self.envs.plan_env()?);
+        let vars = self.envs.inherit(parent_env).plan_env()?;
+        let mail = self.render(ctx, &vars)?;
+        match self.smtp {
+            Some(ref smtp) => println!("  via smtp {}", smtp.host),
+            None => println!("  via sendmail"),
+        }
+        println!("  From: {}", mail.from);
+        println!("  To: {}", mail.to.join(", "));
+        if !mail.cc.is_empty() {
+            println!("  Cc: {}", mail.cc.join(", "));
+        }
+        println!("  Subject: {}", mail.subject);
+        println!();
+        for line in mail.body.lines() {
+            println!("  {}", line);
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn envs(&self) -> &Envs {
+        &self.envs
+    }
+}
+
+impl EmailAction {
+    fn render(&self, ctx: &Context, vars: &HashMap<String, String>) -> anyhow::Result<Mail> {
+        let hbs = handlebars();
+        let to = render_addresses(&hbs, &self.to, vars)?;
+        if to.is_empty() {
+            return Err(anyhow!("No recipient of mail, `to` is rendered empty"));
+        }
+        let from = match self.from {
+            Some(ref from) => hbs.render_template(from, vars)?,
+            None => default_from(),
+        };
+        let subject = match self.subject {
+            Some(ref subject) => hbs.render_template(subject, vars)?,
+            None => headline(ctx, vars),
+        };
+        let body = match self.body {
+            Some(ref body) => hbs.render_template(body, vars)?,
+            None => summary(ctx, vars, ctx.commits()),
+        };
+        Ok(Mail {
+            from,
+            to,
+            cc: render_addresses(&hbs, &self.cc, vars)?,
+            subject,
+            body,
+        })
+    }
+}
+
+impl Mail {
+    fn message(&self) -> anyhow::Result<Message> {
+        let mut builder = Message::builder()
+            .from(parse_mailbox(&self.from)?)
+            .subject(&self.subject);
+        for to in &self.to {
+            builder = builder.to(parse_mailbox(to)?);
+        }
+        for cc in &self.cc {
+            builder = builder.cc(parse_mailbox(cc)?);
+        }
+        Ok(builder
+            .header(ContentType::TEXT_PLAIN)
+            .body(self.body.clone())?)
+    }
+}
+
+impl SmtpOptions {
+    fn render_env(
+        &self,
+        hbs: &Handlebars,
+        variables: &HashMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        let render = |value: &Option<String>| -> anyhow::Result<Option<String>> {
+            match value {
+                Some(value) => Ok(Some(hbs.render_template(value, variables)?)),
+                None => Ok(None),
+            }
+        };
+        Ok(SmtpOptions {
+            host: hbs.render_template(&self.host, variables)?,
+            port: self.port,
+            security: self.security,
+            username: render(&self.username)?,
+            password: render(&self.password)?,
+            timeout: self.timeout,
+        })
+    }
+
+    fn transport(&self) -> anyhow::Result<SmtpTransport> {
+        let mut builder = match self.security {
+            Security::Starttls => SmtpTransport::starttls_relay(&self.host)?,
+            Security::Tls => SmtpTransport::relay(&self.host)?,
+            Security::None => SmtpTransport::builder_dangerous(&self.host),
+        };
+        if let Some(port) = self.port {
+            builder = builder.port(port);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(Some(Duration::from_secs(timeout)));
+        }
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => {
+                builder = builder.credentials(Credentials::new(username.clone(), password.clone()))
+            }
+            (None, None) => {}
+            _ => return Err(anyhow!("Both username and password should be specified")),
+        }
+        Ok(builder.build())
+    }
+}
+
+/// Render addresses, split those separated by `,`, with empty and duplicate
+/// ones skipped
+fn render_addresses(
+    hbs: &Handlebars,
+    templates: &[String],
+    vars: &HashMap<String, String>,
+) -> anyhow::Result<Vec<String>> {
+    let mut addresses: Vec<String> = Vec::new();
+    for template in templates {
+        let rendered = hbs.render_template(template, vars)?;
+        for address in rendered.split(',').map(str::trim) {
+            if !address.is_empty() && !addresses.iter().any(|a| a == address) {
+                addresses.push(address.to_string());
+            }
+        }
+    }
+    Ok(addresses)
+}
+
+fn parse_mailbox(address: &str) -> anyhow::Result<Mailbox> {
+    address
+        .parse()
+        .map_err(|err| anyhow!("Invalid mail address '{}': {}", address, err))
+}
+
+fn default_from() -> String {
+    let hostname = gethostname()
+        .ok()
+        .and_then(|name| name.into_string().ok())
+        .unwrap_or_else(|| "localhost".to_string());
+    format!("arrow@{}", hostname)
+}
+
+/// Summary of status, commits and authors, and output of failed action
+fn summary(ctx: &Context, vars: &HashMap<String, String>, commits: &[Commit]) -> String {
+    let mut lines = vec![headline(ctx, vars)];
+    if !commits.is_empty() {
+        lines.push(String::new());
+        lines.push("Commits:".to_string());
+        lines.extend(commit_lines(commits));
+        let mut authors: Vec<String> = Vec::new();
+        for commit in commits {
+            let author = format!("{} <{}>", commit.author_name, commit.author_email);
+            if !authors.contains(&author) {
+                authors.push(author);
+            }
+        }
+        lines.push(String::new());
+        lines.push(format!("Authors: {}", authors.join(", ")));
+    }
+    if let (Some(action), Some(output)) = (vars.get(FAILED_ACTION_ENV), vars.get(FAILED_OUTPUT_ENV))
+    {
+        lines.push(String::new());
+        lines.push(format!("Output of {}:", action));
+        lines.extend(output.lines().map(|line| format!("    {}", line)));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{DURATION_ENV, PIPELINE_ENV, STATUS_ENV};
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn email(yaml: &str) -> EmailAction {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn context() -> Context {
+        let mut ctx = Context::default();
+        ctx.refname = "refs/heads/main".to_string();
+        ctx.old_rev = "1".repeat(40);
+        ctx.new_rev = "2".repeat(40);
+        ctx.branch = "main".to_string();
+        ctx.repo_name = "shop".to_string();
+        ctx
+    }
+
+    fn failed_vars() -> HashMap<String, String> {
+        HashMap::from([
+            (PIPELINE_ENV.to_string(), "deploy".to_string()),
+            (STATUS_ENV.to_string(), "failure".to_string()),
+            (DURATION_ENV.to_string(), "3s".to_string()),
+            (FAILED_ACTION_ENV.to_string(), "build".to_string()),
+            (
+                FAILED_OUTPUT_ENV.to_string(),
+                "make: *** [all] Error 1\nbuild failed".to_string(),
+            ),
+            ("AUTHOR".to_string(), "alice@example.com".to_string()),
+        ])
+    }
+
+    fn commit(i: usize, author: &str) -> Commit {
+        Commit {
+            rev: format!("{:040}", i),
+            author_name: author.to_string(),
+            author_email: format!("{}@example.com", author.to_lowercase()),
+            committer_name: author.to_string(),
+            committer_email: format!("{}@example.com", author.to_lowercase()),
+            subject: format!("Change {}", i),
+            message: format!("Change {}", i),
+        }
+    }
+
+    #[test]
+    fn addresses_split_and_deduplicated() {
+        let vars = HashMap::from([
+            ("AUTHOR".to_string(), "alice@example.com".to_string()),
+            (
+                "TEAM".to_string(),
+                "bob@example.com, ,alice@example.com,".to_string(),
+            ),
+            ("NONE".to_string(), String::new()),
+        ]);
+        let templates =
+            ["{{AUTHOR}}", "{{NONE}}", "{{TEAM}}", " ops@example.com "].map(String::from);
+        assert_eq!(
+            render_addresses(&handlebars(), &templates, &vars).unwrap(),
+            ["alice@example.com", "bob@example.com", "ops@example.com"]
+        );
+    }
+
+    #[test]
+    fn empty_recipients_fail() {
+        let action = email("{name: mail, to: ['{{NONE}}', '']}");
+        let vars = HashMap::from([("NONE".to_string(), String::new())]);
+        let err = action.render(&context(), &vars).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "No recipient of mail, `to` is rendered empty"
+        );
+    }
+
+    #[test]
+    fn summary_of_failure() {
+        let commits = [commit(2, "Bob"), commit(1, "Alice"), commit(0, "Bob")];
+        let text = summary(&context(), &failed_vars(), &commits);
+        assert_eq!(
+            text,
+            "[shop] deploy failed at build on main (1111111..2222222) in 3s\n\
+             \n\
+             Commits:\n\
+             - 0000000 Change 2 (Bob)\n\
+             - 0000000 Change 1 (Alice)\n\
+             - 0000000 Change 0 (Bob)\n\
+             \n\
+             Authors: Bob <bob@example.com>, Alice <alice@example.com>\n\
+             \n\
+             Output of build:\n    \
+             make: *** [all] Error 1\n    \
+             build failed"
+        );
+
+        let vars = HashMap::from([
+            (PIPELINE_ENV.to_string(), "deploy".to_string()),
+            (STATUS_ENV.to_string(), "success".to_string()),
+            (DURATION_ENV.to_string(), "3s".to_string()),
+        ]);
+        assert_eq!(
+            summary(&context(), &vars, &[]),
+            "[shop] deploy succeeded on main (1111111..2222222) in 3s"
+        );
+    }
+
+    #[test]
+    fn send_by_sendmail() {
+        let dir = tempfile::tempdir().unwrap();
+        let sendmail = dir.path().join("sendmail");
+        fs::write(
+            &sendmail,
+            format!(
+                "#!/bin/sh\nprintf '%s\\n' \"$@\" > '{dir}/args'\ncat > '{dir}/mail'\n",
+                dir = dir.path().display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&sendmail, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let action = email(&format!(
+            "{{name: mail, from: arrow@example.com, to: ['{{{{AUTHOR}}}}', 'ops@example.com'], \
+             cc: 'lead@example.com', sendmail: '{}'}}",
+            sendmail.display()
+        ));
+        action
+            .run(&context(), &Envs::from_context(failed_vars()))
+            .unwrap();
+
+        let args = fs::read_to_string(dir.path().join("args")).unwrap();
+        let args: Vec<&str> = args.lines().collect();
+        assert_eq!(args[..4], ["-i", "-f", "arrow@example.com", "--"]);
+        let mut recipients = args[4..].to_vec();
+        recipients.sort();
+        assert_eq!(
+            recipients,
+            ["alice@example.com", "lead@example.com", "ops@example.com"]
+        );
+
+        let mail = fs::read_to_string(dir.path().join("mail")).unwrap();
+        for header in [
+            "From: arrow@example.com\r\n",
+            "To: alice@example.com, ops@example.com\r\n",
+            "Cc: lead@example.com\r\n",
+            "Subject: [shop] deploy failed at build on main (1111111..2222222) in 3s\r\n",
+            "Content-Type: text/plain; charset=utf-8\r\n",
+        ] {
+            assert!(mail.contains(header), "{} not in {}", header, mail);
+        }
+        assert!(mail.contains("Output of build:"), "{}", mail);
+        assert!(mail.contains("build failed"), "{}", mail);
+    }
+}
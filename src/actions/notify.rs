@@ -0,0 +1,459 @@
+use crate::actions::webhook::{handlebars, HookSpec, TlsOptions};
+use crate::actions::IAction;
+use crate::envs::Envs;
+use crate::helper::print_vars;
+use crate::pipeline::{DURATION_ENV, FAILED_ACTION_ENV, PIPELINE_ENV, STATUS_ENV};
+use crate::repo::{short_rev, Commit, Context};
+use anyhow::anyhow;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Max commits listed in message, the rest are counted
+const MAX_COMMITS: usize = 10;
+
+/// Max length of discord message content
+const DISCORD_MAX_CONTENT: usize = 2000;
+
+/// Post a message of pipeline status to chat
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyAction {
+    name: String,
+    provider: Provider,
+    /// Incoming webhook url, or homeserver url for matrix
+    url: String,
+    /// Message template rendered with env, by default a summary of the
+    /// pipeline and commits
+    message: Option<String>,
+    /// Channel to post to, for slack and mattermost
+    channel: Option<String>,
+    /// Name to post as, for slack, mattermost and discord
+    username: Option<String>,
+    /// Room id for matrix, e.g. `!abc:example.org`
+    room: Option<String>,
+    /// Access token for matrix
+    token: Option<String>,
+    /// Proxy url, by default per `HTTPS_PROXY`, `HTTP_PROXY`, `ALL_PROXY`
+    /// and `NO_PROXY` of env
+    proxy: Option<String>,
+    #[serde(default)]
+    tls: TlsOptions,
+
+    #[serde(flatten)]
+    envs: Envs,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum Provider {
+    Slack,
+    Mattermost,
+    Discord,
+    Matrix,
+}
+
+impl Provider {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Provider::Slack => "slack",
+            Provider::Mattermost => "mattermost",
+            Provider::Discord => "discord",
+            Provider::Matrix => "matrix",
+        }
+    }
+}
+
+impl IAction for NotifyAction {
+    fn run(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
+        println!("### {}\n", self.name);
+        let vars = self.envs.inherit(parent_env).build_env()?;
+        let hook = self.build_request(ctx, &vars)?;
+        let agent = hook.agent(&ctx.workdir())?;
+        hook.execute(&agent, &ctx.workdir())?;
+        Ok(())
+    }
+
+    fn plan(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
+        println!("### {}\n", self.name);
+        print_vars(&self.envs.plan_env()?);
+        // webhook urls of chats are secrets themselves
+        let vars = self.envs.inherit(parent_env).plan_env()?;
+        let (url, body) = self.payload(ctx, &vars)?;
+        println!("  {} {}", self.provider.as_str(), url);
+        println!();
+        println!("  {}", body);
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn envs(&self) -> &Envs {
+        &self.envs
+    }
+}
+
+impl NotifyAction {
+    /// Request to post message by webhook
+    fn build_request(
+        &self,
+        ctx: &Context,
+        vars: &HashMap<String, String>,
+    ) -> anyhow::Result<HookSpec> {
+        let (url, body) = self.payload(ctx, vars)?;
+        let hook = match self.provider {
+            Provider::Matrix => {
+                let token = self.required("token", self.token.as_ref(), vars)?;
+                HookSpec::json("PUT", url, &body)?
+                    .with_header("Authorization", format!("Bearer {}", token))
+            }
+            _ => HookSpec::json("POST", url, &body)?,
+        };
+        hook.with_transport(self.proxy.as_ref(), &self.tls, vars)
+    }
+
+    /// Url and body of provider to post message to
+    fn payload(
+        &self,
+        ctx: &Context,
+        vars: &HashMap<String, String>,
+    ) -> anyhow::Result<(String, Value)> {
+        let hbs = handlebars();
+        let url = hbs.render_template(&self.url, vars)?;
+        let text = match self.message {
+            Some(ref message) => hbs.render_template(message, vars)?,
+            None => summary(ctx, vars, ctx.commits()),
+        };
+        let mut body = Map::new();
+        let url = match self.provider {
+            Provider::Slack | Provider::Mattermost => {
+                body.insert("text".to_string(), json!(text));
+                if let Some(ref channel) = self.channel {
+                    body.insert(
+                        "channel".to_string(),
+                        json!(hbs.render_template(channel, vars)?),
+                    );
+                }
+                if let Some(ref username) = self.username {
+                    body.insert(
+                        "username".to_string(),
+                        json!(hbs.render_template(username, vars)?),
+                    );
+                }
+                url
+            }
+            Provider::Discord => {
+                let content: String = text.chars().take(DISCORD_MAX_CONTENT).collect();
+                body.insert("content".to_string(), json!(content));
+                if let Some(ref username) = self.username {
+                    body.insert(
+                        "username".to_string(),
+                        json!(hbs.render_template(username, vars)?),
+                    );
+                }
+                url
+            }
+            Provider::Matrix => {
+                let room = self.required("room", self.room.as_ref(), vars)?;
+                body.insert("msgtype".to_string(), json!("m.text"));
+                body.insert("body".to_string(), json!(text));
+                format!(
+                    "{}/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+                    url.trim_end_matches('/'),
+                    encode_path_segment(&room),
+                    transaction_id()
+                )
+            }
+        };
+        Ok((url, Value::Object(body)))
+    }
+
+    /// Render optional field that is required by provider
+    fn required(
+        &self,
+        field: &str,
+        value: Option<&String>,
+        vars: &HashMap<String, String>,
+    ) -> anyhow::Result<String> {
+        let value = value.ok_or_else(|| {
+            anyhow!(
+                "{} is required for {} notification",
+                field,
+                self.provider.as_str()
+            )
+        })?;
+        Ok(handlebars().render_template(value, vars)?)
+    }
+}
+
+/// Summary of pipeline status and commits
+fn summary(ctx: &Context, vars: &HashMap<String, String>, commits: &[Commit]) -> String {
+    let mut lines = vec![headline(ctx, vars)];
+    lines.extend(commit_lines(commits));
+    lines.join("\n")
+}
+
+/// One line of pipeline status, from status env of pipeline
+pub fn headline(ctx: &Context, vars: &HashMap<String, String>) -> String {
+    let get = |key: &str| vars.get(key).map(String::as_str).unwrap_or_default();
+    let status = match vars.get(FAILED_ACTION_ENV) {
+        Some(action) => format!("failed at {}", action),
+        None if get(STATUS_ENV) == "failure" => "failed".to_string(),
+        None => "succeeded".to_string(),
+    };
+    format!(
+        "[{}] {} {} on {} ({}..{}) in {}",
+        ctx.repo_name,
+        get(PIPELINE_ENV),
+        status,
+        ctx.branch_or_rev(),
+        short_rev(&ctx.old_rev),
+        short_rev(&ctx.new_rev),
+        get(DURATION_ENV)
+    )
+}
+
+/// Lines of commits, up to `MAX_COMMITS`
+pub fn commit_lines(commits: &[Commit]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for commit in commits.iter().take(MAX_COMMITS) {
+        lines.push(format!(
+            "- {} {} ({})",
+            commit.short_rev(),
+            commit.subject,
+            commit.author_name
+        ));
+    }
+    if commits.len() > MAX_COMMITS {
+        lines.push(format!("- and {} more", commits.len() - MAX_COMMITS));
+    }
+    lines
+}
+
+/// Percent encode all but unreserved chars of url
+fn encode_path_segment(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Transaction id required by matrix to send a message, new for each run,
+/// as the homeserver takes a reused id as the same message
+fn transaction_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("arrow-{}", nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    /// Request received by mock server
+    struct Request {
+        method: String,
+        path: String,
+        headers: HashMap<String, String>,
+        body: Value,
+    }
+
+    /// Serve one request on a local port with the status, returns url of
+    /// server and the request received
+    fn serve(status: u16) -> (String, Receiver<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&mut stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut parts = line.split_whitespace();
+            let method = parts.next().unwrap().to_string();
+            let path = parts.next().unwrap().to_string();
+            let mut headers = HashMap::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                match line.trim_end().split_once(": ") {
+                    Some((key, value)) => headers.insert(key.to_lowercase(), value.to_string()),
+                    None => break,
+                };
+            }
+            let length = headers["content-length"].parse().unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 {} OK\r\ncontent-length: 0\r\n\r\n",
+                status
+            )
+            .unwrap();
+            sender
+                .send(Request {
+                    method,
+                    path,
+                    headers,
+                    body: serde_json::from_slice(&body).unwrap(),
+                })
+                .unwrap();
+        });
+        (url, receiver)
+    }
+
+    fn notify(yaml: &str) -> NotifyAction {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn context() -> Context {
+        let mut ctx = Context::default();
+        ctx.refname = "refs/heads/main".to_string();
+        ctx.old_rev = "1".repeat(40);
+        ctx.new_rev = "2".repeat(40);
+        ctx.branch = "main".to_string();
+        ctx.repo_name = "shop".to_string();
+        ctx
+    }
+
+    fn parent_env() -> Envs {
+        Envs::from_context(HashMap::from([
+            (PIPELINE_ENV.to_string(), "deploy".to_string()),
+            (STATUS_ENV.to_string(), "failure".to_string()),
+            (FAILED_ACTION_ENV.to_string(), "build".to_string()),
+            (DURATION_ENV.to_string(), "3s".to_string()),
+            ("CHANNEL".to_string(), "#ops".to_string()),
+        ]))
+    }
+
+    fn commit(i: usize) -> Commit {
+        Commit {
+            rev: format!("{:040}", i),
+            author_name: "Alice".to_string(),
+            author_email: "alice@example.com".to_string(),
+            committer_name: "Alice".to_string(),
+            committer_email: "alice@example.com".to_string(),
+            subject: format!("Change {}", i),
+            message: format!("Change {}\n", i),
+        }
+    }
+
+    #[test]
+    fn post_to_slack() {
+        let (url, requests) = serve(200);
+        let action = notify(&format!(
+            "{{name: chat, provider: slack, url: '{}/hooks/abc', channel: '{{{{CHANNEL}}}}', \
+             username: arrow}}",
+            url
+        ));
+        action.run(&context(), &parent_env()).unwrap();
+        let request = requests.recv().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/hooks/abc");
+        assert_eq!(request.headers["content-type"], "application/json");
+        assert_eq!(
+            request.body,
+            json!({
+                "text": "[shop] deploy failed at build on main (1111111..2222222) in 3s",
+                "channel": "#ops",
+                "username": "arrow",
+            })
+        );
+    }
+
+    #[test]
+    fn post_to_discord() {
+        let (url, requests) = serve(204);
+        let action = notify(&format!(
+            "{{name: chat, provider: discord, url: '{}/api/webhooks/1/t', \
+             message: '{{{{CHANNEL}}}} {}'}}",
+            url,
+            "x".repeat(DISCORD_MAX_CONTENT)
+        ));
+        action.run(&context(), &parent_env()).unwrap();
+        let request = requests.recv().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/webhooks/1/t");
+        let content = request.body["content"].as_str().unwrap();
+        assert_eq!(content.len(), DISCORD_MAX_CONTENT);
+        assert!(content.starts_with("#ops xx"));
+    }
+
+    #[test]
+    fn put_to_matrix() {
+        let (url, requests) = serve(200);
+        let action = notify(&format!(
+            "{{name: chat, provider: matrix, url: '{}/', room: '!abc:example.org', \
+             token: secret, message: done}}",
+            url
+        ));
+        action.run(&context(), &parent_env()).unwrap();
+        let request = requests.recv().unwrap();
+        assert_eq!(request.method, "PUT");
+        assert!(
+            request.path.starts_with(
+                "/_matrix/client/v3/rooms/%21abc%3Aexample.org/send/m.room.message/arrow-"
+            ),
+            "{}",
+            request.path
+        );
+        assert_eq!(request.headers["authorization"], "Bearer secret");
+        assert_eq!(request.body, json!({"msgtype": "m.text", "body": "done"}));
+    }
+
+    #[test]
+    fn matrix_requires_room_and_token() {
+        let action = notify("{name: chat, provider: matrix, url: 'http://m', token: t}");
+        let err = action.run(&context(), &parent_env()).unwrap_err();
+        assert_eq!(err.to_string(), "room is required for matrix notification");
+        let action = notify("{name: chat, provider: matrix, url: 'http://m', room: r}");
+        let err = action.run(&context(), &parent_env()).unwrap_err();
+        assert_eq!(err.to_string(), "token is required for matrix notification");
+    }
+
+    #[test]
+    fn error_status_fails() {
+        let (url, requests) = serve(500);
+        let action = notify(&format!(
+            "{{name: chat, provider: mattermost, url: '{}'}}",
+            url
+        ));
+        let err = action.run(&context(), &parent_env()).unwrap_err();
+        assert_eq!(err.to_string(), "Unexpected status 500");
+        assert!(requests.recv().is_ok());
+    }
+
+    #[test]
+    fn summary_of_commits() {
+        let vars = HashMap::from([
+            (PIPELINE_ENV.to_string(), "deploy".to_string()),
+            (STATUS_ENV.to_string(), "success".to_string()),
+            (DURATION_ENV.to_string(), "1m 2s".to_string()),
+        ]);
+        let commits: Vec<Commit> = (1..=MAX_COMMITS + 2).map(commit).collect();
+        let text = summary(&context(), &vars, &commits);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[0],
+            "[shop] deploy succeeded on main (1111111..2222222) in 1m 2s"
+        );
+        assert_eq!(lines[1], "- 0000000 Change 1 (Alice)");
+        assert_eq!(lines.len(), MAX_COMMITS + 2);
+        assert_eq!(lines[MAX_COMMITS + 1], "- and 2 more");
+    }
+}
@@ -0,0 +1,354 @@
+use crate::actions::shell::stream_output;
+use crate::actions::ssh::{Host, SshOptions};
+use crate::actions::IAction;
+use crate::decode;
+use crate::envs::Envs;
+use crate::helper::{is_inside, print_vars, shell_quote};
+use crate::repo::Context;
+use anyhow::anyhow;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Sync files of workspace to remote hosts with rsync over ssh
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RsyncAction {
+    name: String,
+    /// Path or glob relative to workspace, trailing `/` syncs content of
+    /// dir rather than dir itself
+    source: String,
+    /// Dir on remote hosts
+    target: String,
+    /// Delete files in target not present in source
+    #[serde(default)]
+    delete: bool,
+    /// Patterns of files not to sync
+    #[serde(default, deserialize_with = "decode::string_or_seq")]
+    #[schemars(schema_with = "decode::string_or_seq_schema")]
+    exclude: Vec<String>,
+    /// Sync into a new dir under `releases` of target, then switch
+    /// `current` symlink to it once done
+    release: Option<ReleaseOptions>,
+    /// Extra rsync args
+    #[serde(default)]
+    rsync_args: Vec<String>,
+    #[serde(flatten)]
+    ssh: SshOptions,
+    #[serde(flatten)]
+    envs: Envs,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct ReleaseOptions {
+    /// Number of releases to keep, including current one
+    #[serde(default = "default_keep")]
+    keep: usize,
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+const RELEASES_DIR: &str = "releases";
+const CURRENT_LINK: &str = "current";
+
+/// Dir of previous release, relative to the new release dir in `releases`
+fn previous_release() -> String {
+    format!("../../{}/", CURRENT_LINK)
+}
+
+impl IAction for RsyncAction {
+    fn run(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
+        println!("### {}\n", self.name);
+        let vars = self.envs.inherit(parent_env).build_env()?;
+        let sources = self.sources(ctx)?;
+        let release = self.release_name(ctx);
+        for host in self.ssh.hosts() {
+            let target = match release {
+                Some(ref release) => {
+                    let dir = format!("{}/{}/{}", self.target, RELEASES_DIR, release);
+                    self.remote(host, &format!("mkdir -p {}", shell_quote(&dir)), &vars)?;
+                    dir
+                }
+                None => self.target.clone(),
+            };
+            let link_dest = release.as_ref().map(|_| previous_release());
+            let args = self.rsync_args(host, &sources, &target, link_dest.as_deref());
+            println!("  [{}] rsync {}", host.spec, args.join(" "));
+            let mut cmd = Command::new("rsync");
+            cmd.args(&args).current_dir(ctx.workdir()).envs(&vars);
+            let status = stream_output(&mut cmd)?;
+            if !status.success() {
+                return Err(anyhow!("rsync to {} exited with {}", host.spec, status));
+            }
+            if let (Some(ref release), Some(ref options)) = (&release, &self.release) {
+                self.remote(host, &self.switch_script(release, options.keep), &vars)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn plan(&self, _ctx: &Context, _parent_env: &Envs) -> anyhow::Result<()> {
+        println!("### {}\n", self.name);
+        print_vars(&self.envs.plan_env()?);
+        let (target, link_dest) = match self.release {
+            Some(_) => (
+                format!("{}/{}/<release>", self.target, RELEASES_DIR),
+                Some(previous_release()),
+            ),
+            None => (self.target.clone(), None),
+        };
+        for host in self.ssh.hosts() {
+            let args = self.rsync_args(
+                host,
+                std::slice::from_ref(&self.source),
+                &target,
+                link_dest.as_deref(),
+            );
+            println!("  $ rsync {}", args.join(" "));
+        }
+        if let Some(ref options) = self.release {
+            println!(
+                "  then switch {}/{} to the release, keep {} releases",
+                self.target, CURRENT_LINK, options.keep
+            );
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn envs(&self) -> &Envs {
+        &self.envs
+    }
+}
+
+impl RsyncAction {
+    /// Expand source to paths relative to workspace
+    fn sources(&self, ctx: &Context) -> anyhow::Result<Vec<String>> {
+        if !is_inside(Path::new(&self.source)) {
+            return Err(anyhow!(
+                "source '{}' should be relative and inside workspace",
+                self.source
+            ));
+        }
+        if !self.source.contains(['*', '?', '[']) {
+            return Ok(vec![self.source.clone()]);
+        }
+        let workdir = ctx.workdir();
+        let prefix = glob::Pattern::escape(&workdir.to_string_lossy());
+        let pattern = format!("{}/{}", prefix, self.source);
+        let mut sources = Vec::new();
+        for entry in glob::glob(&pattern)? {
+            let path = entry?;
+            let relative = path.strip_prefix(&workdir).unwrap_or(&path);
+            sources.push(relative.to_string_lossy().to_string());
+        }
+        if sources.is_empty() {
+            return Err(anyhow!("No file matches source '{}'", self.source));
+        }
+        Ok(sources)
+    }
+
+    /// Name of release dir, as `<timestamp>-<short sha>`
+    fn release_name(&self, ctx: &Context) -> Option<String> {
+        self.release.as_ref()?;
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let short = ctx.new_rev.get(..7).unwrap_or(&ctx.new_rev);
+        Some(format!("{}-{}", secs, short))
+    }
+
+    /// Args of rsync, with files of `link_dest` hard linked if unchanged.
+    /// Remote paths are passed by `--protect-args` rather than through the
+    /// remote shell, so they need no quoting.
+    fn rsync_args(
+        &self,
+        host: &Host,
+        sources: &[String],
+        target: &str,
+        link_dest: Option<&str>,
+    ) -> Vec<String> {
+        let mut args = vec!["-az".to_string(), "--protect-args".to_string()];
+        if let Some(dir) = link_dest {
+            args.push(format!("--link-dest={}", dir));
+        }
+        if self.delete {
+            args.push("--delete".to_string());
+        }
+        for pattern in &self.exclude {
+            args.push("--exclude".to_string());
+            args.push(pattern.clone());
+        }
+        let mut ssh = vec!["ssh".to_string()];
+        ssh.extend(self.ssh.ssh_args());
+        if let Some(port) = host.port {
+            ssh.push("-p".to_string());
+            ssh.push(port.to_string());
+        }
+        let ssh: Vec<String> = ssh.iter().map(|arg| shell_quote(arg)).collect();
+        args.push("-e".to_string());
+        args.push(ssh.join(" "));
+        args.extend(self.rsync_args.clone());
+        args.extend(sources.iter().cloned());
+        let address = match host.host.contains(':') {
+            true => format!("[{}]", host.host),
+            false => host.host.clone(),
+        };
+        let destination = match self.ssh.host_user(host) {
+            Some(user) => format!("{}@{}:{}/", user, address, target),
+            None => format!("{}:{}/", address, target),
+        };
+        args.push(destination);
+        args
+    }
+
+    /// Script to switch current symlink to release atomically, and remove
+    /// old releases. The new link is renamed over the current one, by GNU
+    /// `mv -T` or BSD `mv -h`, otherwise it is replaced non-atomically.
+    fn switch_script(&self, release: &str, keep: usize) -> String {
+        let link_tmp = format!("{}.tmp", CURRENT_LINK);
+        [
+            format!("cd {}", shell_quote(&self.target)),
+            format!(
+                "ln -sfn {}/{} {}",
+                RELEASES_DIR,
+                shell_quote(release),
+                link_tmp
+            ),
+            format!(
+                "{{ mv -Tf {tmp} {link} 2>/dev/null || mv -hf {tmp} {link} 2>/dev/null || {{ rm -f {link} && mv -f {tmp} {link}; }}; }}",
+                tmp = link_tmp,
+                link = CURRENT_LINK
+            ),
+            format!(
+                "ls -1 {} | sort -r | tail -n +{} | while read -r old; do rm -rf \"{}/$old\"; done",
+                RELEASES_DIR,
+                keep.max(1) + 1,
+                RELEASES_DIR
+            ),
+        ]
+        .join(" && ")
+    }
+
+    /// Run command on remote host, fail on non-zero exit
+    fn remote(
+        &self,
+        host: &Host,
+        command: &str,
+        vars: &HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        println!("  [{}] {}", host.spec, command);
+        let mut cmd = Command::new("ssh");
+        cmd.args(self.ssh.host_args(host)).arg(command).envs(vars);
+        let status = stream_output(&mut cmd)?;
+        if !status.success() {
+            return Err(anyhow!("ssh to {} exited with {}", host.spec, status));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    fn action(yaml: &str) -> RsyncAction {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn rsync_args_of_release() {
+        let action = action(
+            "{name: deploy, source: dist/, target: /srv/my app, hosts: ['deploy@[::1]:2222'], release: {}}",
+        );
+        let host = &action.ssh.hosts()[0];
+        let args = action.rsync_args(
+            host,
+            &["dist/".to_string()],
+            "/srv/my app/releases/1-abc",
+            Some(&previous_release()),
+        );
+        assert_eq!(
+            args,
+            vec![
+                "-az",
+                "--protect-args",
+                "--link-dest=../../current/",
+                "-e",
+                "'ssh' '-p' '2222'",
+                "dist/",
+                "deploy@[::1]:/srv/my app/releases/1-abc/",
+            ]
+        );
+    }
+
+    /// Run switch script of releases in target dir, with PATH prepended
+    fn switch(action: &RsyncAction, release: &str, path: &str) {
+        fs::create_dir_all(Path::new(&action.target).join(RELEASES_DIR).join(release)).unwrap();
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(action.switch_script(release, 2))
+            .env("PATH", path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    fn check_switch(path: &str) {
+        let dir = TempDir::new().unwrap();
+        let target = dir.path().to_string_lossy();
+        let action = action(&format!(
+            "{{name: deploy, source: dist, target: '{}', hosts: [web], release: {{keep: 2}}}}",
+            target
+        ));
+        for release in ["1-aaa", "2-bbb", "3-ccc"] {
+            switch(&action, release, path);
+            let link = fs::read_link(dir.path().join(CURRENT_LINK)).unwrap();
+            assert_eq!(link, Path::new(RELEASES_DIR).join(release));
+        }
+        let mut releases: Vec<String> = fs::read_dir(dir.path().join(RELEASES_DIR))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        releases.sort();
+        assert_eq!(releases, vec!["2-bbb", "3-ccc"]);
+        assert!(!dir.path().join("current.tmp").exists());
+    }
+
+    #[test]
+    fn switch_release() {
+        check_switch(&std::env::var("PATH").unwrap());
+    }
+
+    #[test]
+    fn switch_release_without_mv_t_or_h() {
+        // mv of neither GNU nor BSD, e.g. of busybox
+        let bin = TempDir::new().unwrap();
+        let mv = bin.path().join("mv");
+        fs::write(
+            &mv,
+            "#!/bin/sh\ncase \"$1\" in -T*|-h*) exit 1;; esac\nexec /bin/mv \"$@\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&mv, fs::Permissions::from_mode(0o755)).unwrap();
+        let path = format!(
+            "{}:{}",
+            bin.path().display(),
+            std::env::var("PATH").unwrap()
+        );
+        check_switch(&path);
+    }
+}
@@ -0,0 +1,288 @@
+use crate::actions::shell::{stream_output, ExecOptions};
+use crate::actions::IAction;
+use crate::envs::Envs;
+use crate::helper::print_vars;
+use crate::repo::Context;
+use anyhow::anyhow;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::fs::Permissions;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+use tempfile::{Builder, TempPath};
+
+/// Run script with arbitrary interpreter, e.g. python3, node, or per its
+/// shebang line. It is run with `sh` if neither is specified.
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptAction {
+    name: String,
+    script: String,
+    /// Interpreter to run script file with, e.g. `python3 -u`
+    interpreter: Option<String>,
+    /// Command to run inline script with, e.g. `["zsh", "-e", "-c"]`
+    shell: Option<Vec<String>>,
+
+    #[serde(flatten)]
+    exec: ExecOptions,
+    #[serde(flatten)]
+    envs: Envs,
+}
+
+/// How script is run
+#[derive(Debug, PartialEq)]
+enum Runner {
+    /// Command line, with script appended as last arg
+    Inline(Vec<String>),
+    /// Command line, with path to script file appended as last arg
+    File(Vec<String>),
+    /// Script file executed directly, per its shebang line
+    Shebang,
+}
+
+const SHEBANG: &str = "#!";
+
+/// Flags for sh and bash to stop at the first failing command
+fn strict_flags(shell: &str) -> &'static [&'static str] {
+    let name = Path::new(shell)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(shell);
+    match name {
+        "sh" | "dash" | "ash" => &["-e"],
+        "bash" => &["-e", "-o", "pipefail"],
+        _ => &[],
+    }
+}
+
+impl IAction for ScriptAction {
+    fn run(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
+        println!("### {}\n", self.name);
+        let vars = self.envs.inherit(parent_env).build_env()?;
+        // keep script file till the process exits
+        let mut script_file: Option<TempPath> = None;
+        let mut cmd = match self.runner()? {
+            Runner::Inline(cmdline) => {
+                let mut cmd = Command::new(&cmdline[0]);
+                cmd.args(&cmdline[1..]).arg(&self.script);
+                cmd
+            }
+            Runner::File(cmdline) => {
+                let path = self.write_script(false)?;
+                let mut cmd = Command::new(&cmdline[0]);
+                cmd.args(&cmdline[1..]).arg(&path);
+                script_file = Some(path);
+                cmd
+            }
+            Runner::Shebang => {
+                let path = self.write_script(true)?;
+                let cmd = Command::new(&path);
+                script_file = Some(path);
+                cmd
+            }
+        };
+        self.exec.apply(ctx, &mut cmd)?;
+        let status = stream_output(cmd.envs(&vars))?;
+        drop(script_file);
+        if !status.success() {
+            return Err(anyhow!("Script of {} exited with {}", self.name, status));
+        }
+        Ok(())
+    }
+
+    fn plan(&self, _ctx: &Context, _parent_env: &Envs) -> anyhow::Result<()> {
+        println!("### {}\n", self.name);
+        print_vars(&self.envs.plan_env()?);
+        self.exec.plan();
+        match self.runner()? {
+            Runner::Inline(cmdline) => println!("  $ {}", cmdline.join(" ")),
+            Runner::File(cmdline) => println!("  $ {} <script>", cmdline.join(" ")),
+            Runner::Shebang => println!("  $ <script>"),
+        }
+        for line in self.script.lines() {
+            println!("    {}", line);
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn envs(&self) -> &Envs {
+        &self.envs
+    }
+}
+
+impl ScriptAction {
+    fn runner(&self) -> anyhow::Result<Runner> {
+        match (&self.interpreter, &self.shell) {
+            (Some(_), Some(_)) => Err(anyhow!(
+                "{}: only one of interpreter and shell could be specified",
+                self.name
+            )),
+            (Some(interpreter), None) => {
+                let mut cmdline: Vec<String> =
+                    interpreter.split_whitespace().map(String::from).collect();
+                if cmdline.is_empty() {
+                    return Err(anyhow!("{}: interpreter is empty", self.name));
+                }
+                let flags = strict_flags(&cmdline[0]).iter().map(|f| f.to_string());
+                cmdline.splice(1..1, flags);
+                Ok(Runner::File(cmdline))
+            }
+            (None, Some(shell)) => {
+                if shell.is_empty() {
+                    return Err(anyhow!("{}: shell is empty", self.name));
+                }
+                Ok(Runner::Inline(shell.clone()))
+            }
+            (None, None) if self.script.starts_with(SHEBANG) => Ok(Runner::Shebang),
+            (None, None) => {
+                let mut cmdline = vec!["sh".to_string()];
+                cmdline.extend(strict_flags("sh").iter().map(|f| f.to_string()));
+                cmdline.push("-c".to_string());
+                Ok(Runner::Inline(cmdline))
+            }
+        }
+    }
+
+    /// Write script to temporary file, which is removed once dropped
+    fn write_script(&self, executable: bool) -> anyhow::Result<TempPath> {
+        let mut file = Builder::new()
+            .prefix("arrow-")
+            .suffix(".script")
+            .tempfile()?;
+        file.write_all(self.script.as_bytes())?;
+        file.flush()?;
+        // readable by others, if it is run as another user
+        let mode = match (executable, self.exec.switches_user()) {
+            (true, true) => 0o755,
+            (true, false) => 0o700,
+            (false, true) => 0o644,
+            (false, false) => 0o600,
+        };
+        file.as_file()
+            .set_permissions(Permissions::from_mode(mode))?;
+        // close it, otherwise it is busy to be executed
+        Ok(file.into_temp_path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(yaml: &str) -> ScriptAction {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn cmdline(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn runner_of_interpreter() {
+        let runner = |interpreter: &str| {
+            script(&format!(
+                "{{name: s, script: x, interpreter: '{}'}}",
+                interpreter
+            ))
+            .runner()
+        };
+        assert_eq!(
+            runner("bash").unwrap(),
+            Runner::File(cmdline(&["bash", "-e", "-o", "pipefail"]))
+        );
+        assert_eq!(
+            runner("/bin/sh -x").unwrap(),
+            Runner::File(cmdline(&["/bin/sh", "-e", "-x"]))
+        );
+        assert_eq!(
+            runner("python3 -u").unwrap(),
+            Runner::File(cmdline(&["python3", "-u"]))
+        );
+        let err = runner(" ").unwrap_err();
+        assert_eq!(err.to_string(), "s: interpreter is empty");
+    }
+
+    #[test]
+    fn runner_of_shell_or_shebang() {
+        let action = script("{name: s, script: x, shell: [zsh, -e, -c]}");
+        assert_eq!(
+            action.runner().unwrap(),
+            Runner::Inline(cmdline(&["zsh", "-e", "-c"]))
+        );
+        let err = script("{name: s, script: x, shell: []}")
+            .runner()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "s: shell is empty");
+
+        let action = script("{name: s, script: \"#!/usr/bin/env node\\nconsole.log(1)\"}");
+        assert_eq!(action.runner().unwrap(), Runner::Shebang);
+        assert_eq!(
+            script("{name: s, script: x}").runner().unwrap(),
+            Runner::Inline(cmdline(&["sh", "-e", "-c"]))
+        );
+
+        let err = script("{name: s, script: x, interpreter: bash, shell: [sh, -c]}")
+            .runner()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "s: only one of interpreter and shell could be specified"
+        );
+    }
+
+    #[test]
+    fn script_file_modes() {
+        let mode = |yaml: &str, executable: bool| {
+            let path = script(yaml).write_script(executable).unwrap();
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "echo hi");
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777
+        };
+        assert_eq!(mode("{name: s, script: echo hi}", false), 0o600);
+        assert_eq!(mode("{name: s, script: echo hi}", true), 0o700);
+        assert_eq!(
+            mode("{name: s, script: echo hi, run_as: nobody}", false),
+            0o644
+        );
+        assert_eq!(
+            mode("{name: s, script: echo hi, run_as: nobody}", true),
+            0o755
+        );
+
+        let path = script("{name: s, script: x}").write_script(false).unwrap();
+        let kept = path.to_path_buf();
+        drop(path);
+        assert!(!kept.exists());
+    }
+
+    #[test]
+    fn run_stops_at_first_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let after = dir.path().join("after");
+        for interpreter in ["sh", "bash"] {
+            let action = script(&format!(
+                "{{name: s, interpreter: {}, script: 'false | false\n\
+                 false\ntouch {}'}}",
+                interpreter,
+                after.display()
+            ));
+            let err = action
+                .run(&Context::default(), &Envs::default())
+                .unwrap_err();
+            assert!(err.to_string().starts_with("Script of s exited with"));
+            assert!(!after.exists(), "{}", interpreter);
+        }
+
+        let action = script(&format!(
+            "{{name: s, script: \"#!/bin/sh\\ntouch {}\"}}",
+            after.display()
+        ));
+        action.run(&Context::default(), &Envs::default()).unwrap();
+        assert!(after.is_file());
+    }
+}
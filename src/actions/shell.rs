@@ -1,53 +1,260 @@
 use crate::actions::IAction;
-use crate::envs::Envs;
+use crate::decode;
+use crate::envs::{Envs, OUTPUT_ENV};
+use crate::helper::{is_inside, print_vars};
+use crate::output;
 use crate::repo::Context;
+use anyhow::{anyhow, Context as _};
+use nix::sys::stat::{umask, Mode};
+use nix::unistd::{chown, User};
+use schemars::JsonSchema;
 use serde::Deserialize;
 use std::{
     io::{BufRead, BufReader},
-    process::{Command, Stdio},
+    os::unix::process::CommandExt,
+    path::Path,
+    process::{Command, ExitStatus, Stdio},
+    thread,
 };
 
-#[derive(Debug, Deserialize, Clone)]
+/// Run script with local shell
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct ShellAction {
     name: String,
     script: String,
     #[serde(skip)]
     shell: String,
 
+    #[serde(flatten)]
+    exec: ExecOptions,
     #[serde(flatten)]
     envs: Envs,
 }
 
+/// Options of local process to run script in
+#[derive(Debug, Deserialize, Clone, Default, JsonSchema)]
+pub struct ExecOptions {
+    /// Dir to run in, relative to workspace
+    working_dir: Option<String>,
+    /// File mode creation mask, in octal as string, e.g. `"022"`, or as
+    /// YAML octal integer, e.g. `0o22`
+    #[serde(default, deserialize_with = "decode::octal")]
+    #[schemars(with = "Option<String>")]
+    umask: Option<u32>,
+    /// User to run as, it requires privilege to switch user
+    run_as: Option<String>,
+}
+
 impl IAction for ShellAction {
     fn run(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
         println!("### {}\n", self.name);
         let envs = self.envs.inherit(parent_env);
         let vars = envs.build_env()?;
-        let mut child = Command::new(self.shell.clone())
-            .arg("-c")
-            .envs(&vars)
-            .arg(&self.script)
-            .stdout(Stdio::piped())
-            .spawn()?;
-        if let Some(ref mut stdout) = child.stdout {
-            let reader = BufReader::new(stdout);
-            for line in reader.lines() {
-                match line {
-                    Ok(line) => println!("  {}", line),
-                    Err(err) => eprintln!("Error: {}", err),
-                }
-            }
+        let mut cmd = Command::new(&self.shell);
+        cmd.arg("-c").envs(&vars).arg(&self.script);
+        self.exec.apply(ctx, &mut cmd)?;
+        let status = stream_output(&mut cmd)?;
+        if !status.success() {
+            return Err(anyhow!("Script of {} exited with {}", self.name, status));
         }
-        let _ = child.wait()?;
+        Ok(())
+    }
 
+    fn plan(&self, _ctx: &Context, _parent_env: &Envs) -> anyhow::Result<()> {
+        println!("### {}\n", self.name);
+        print_vars(&self.envs.plan_env()?);
+        self.exec.plan();
+        println!("  $ {} -c", self.shell);
+        for line in self.script.lines() {
+            println!("    {}", line);
+        }
         Ok(())
     }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn envs(&self) -> &Envs {
+        &self.envs
+    }
 }
 
 impl ShellAction {
     pub fn set_shell(&self, name: String) -> Self {
         let mut action = self.clone();
         action.shell = name;
-        return action;
+        action
+    }
+}
+
+impl ExecOptions {
+    /// Apply options to command
+    pub fn apply(&self, ctx: &Context, cmd: &mut Command) -> anyhow::Result<()> {
+        if let Some(ref dir) = self.working_dir {
+            let relative = Path::new(dir);
+            if !is_inside(relative) {
+                return Err(anyhow!(
+                    "working_dir '{}' should be relative and inside workspace",
+                    dir
+                ));
+            }
+            let path = ctx.workdir().join(relative);
+            if !path.is_dir() {
+                return Err(anyhow!("working_dir {} is not a dir", path.display()));
+            }
+            cmd.current_dir(path);
+        }
+        if let Some(ref name) = self.run_as {
+            let user = User::from_name(name)
+                .with_context(|| format!("Failed to look up user {}", name))?
+                .ok_or_else(|| anyhow!("User {} not found", name))?;
+            hand_over_output_env(cmd, &user)?;
+            cmd.uid(user.uid.as_raw())
+                .gid(user.gid.as_raw())
+                .env("HOME", &user.dir)
+                .env("USER", &user.name)
+                .env("LOGNAME", &user.name);
+        }
+        if let Some(mask) = self.umask {
+            let mask = Mode::from_bits_truncate(mask);
+            // SAFETY: umask is async-signal-safe, thus fine to call after fork
+            unsafe {
+                cmd.pre_exec(move || {
+                    umask(mask);
+                    Ok(())
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether process runs as another user
+    pub fn switches_user(&self) -> bool {
+        self.run_as.is_some()
+    }
+
+    /// Print options that are set
+    pub fn plan(&self) {
+        if let Some(ref dir) = self.working_dir {
+            println!("  working_dir: {}", dir);
+        }
+        if let Some(mask) = self.umask {
+            println!("  umask: {:03o}", mask);
+        }
+        if let Some(ref user) = self.run_as {
+            println!("  run_as: {}", user);
+        }
+    }
+}
+
+/// Chown the output env file of `$ARROW_ENV` to user, so that the process
+/// run as the user can write outputs to it
+fn hand_over_output_env(cmd: &Command, user: &User) -> anyhow::Result<()> {
+    let path = cmd
+        .get_envs()
+        .find(|(key, _)| *key == OUTPUT_ENV)
+        .and_then(|(_, value)| value);
+    if let Some(path) = path.map(Path::new).filter(|path| path.is_file()) {
+        chown(path, Some(user.uid), Some(user.gid))
+            .with_context(|| format!("Failed to chown {} to {}", path.display(), user.name))?;
+    }
+    Ok(())
+}
+
+/// Spawn command and print its stdout and stderr line by line, returns
+/// exit status when it is done.
+pub fn stream_output(cmd: &mut Command) -> anyhow::Result<ExitStatus> {
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    thread::scope(|scope| {
+        if let Some(stderr) = stderr {
+            scope.spawn(|| {
+                for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                    output::print_err(&line);
+                }
+            });
+        }
+        if let Some(stdout) = stdout {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    Ok(line) => output::print_out(&line),
+                    Err(err) => eprintln!("Error: {}", err),
+                }
+            }
+        }
+    });
+    Ok(child.wait()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::geteuid;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::{NamedTempFile, TempDir};
+
+    fn exec(yaml: &str) -> ExecOptions {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn shell_goes_on_after_failed_command() {
+        let dir = TempDir::new().unwrap();
+        let after = dir.path().join("after");
+        let action: ShellAction = serde_yaml::from_str(&format!(
+            "{{name: test, script: 'false; false | true; touch {}'}}",
+            after.display()
+        ))
+        .unwrap();
+        for shell in ["sh", "bash"] {
+            let action = action.set_shell(shell.to_string());
+            action.run(&Context::default(), &Envs::default()).unwrap();
+            assert!(after.is_file(), "{}", shell);
+            std::fs::remove_file(&after).unwrap();
+        }
+
+        let action: ShellAction = serde_yaml::from_str("{name: test, script: 'exit 3'}").unwrap();
+        let err = action
+            .set_shell("sh".to_string())
+            .run(&Context::default(), &Envs::default())
+            .unwrap_err();
+        assert!(err.to_string().contains("exit status: 3"), "{}", err);
+    }
+
+    #[test]
+    fn umask_applies_to_created_files() {
+        let dir = TempDir::new().unwrap();
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("touch file").current_dir(dir.path());
+        exec("umask: 0o27")
+            .apply(&Context::default(), &mut cmd)
+            .unwrap();
+        assert!(cmd.status().unwrap().success());
+        let mode = std::fs::metadata(dir.path().join("file"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+
+    #[test]
+    fn run_as_can_write_output_env() {
+        if !geteuid().is_root() {
+            return;
+        }
+        let output = NamedTempFile::new().unwrap();
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg("echo USER_IS=$(id -un) >> \"$ARROW_ENV\"")
+            .env(OUTPUT_ENV, output.path());
+        exec("run_as: nobody")
+            .apply(&Context::default(), &mut cmd)
+            .unwrap();
+        assert!(cmd.status().unwrap().success());
+        let content = std::fs::read_to_string(output.path()).unwrap();
+        assert_eq!(content, "USER_IS=nobody\n");
     }
 }
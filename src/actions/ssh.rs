@@ -1,77 +1,928 @@
 use crate::actions::IAction;
 use crate::decode;
 use crate::envs::Envs;
+use crate::helper::{format_duration, is_env_name, print_vars, shell_quote};
+use crate::matcher::GlobSet;
+use crate::output;
 use crate::repo::Context;
-use serde::Deserialize;
+use anyhow::anyhow;
+use schemars::JsonSchema;
+use serde::{de, Deserialize, Deserializer};
+#[cfg(feature = "native-ssh")]
+mod native;
+
 use std::{
+    collections::HashMap,
     io::{BufRead, BufReader, Write},
-    process::{Command, Stdio},
+    process::{Child, Command, ExitStatus, Stdio},
+    thread,
+    time::{Duration, Instant},
 };
 
-#[derive(Debug, Deserialize)]
+/// Run script on remote hosts over ssh
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct SshAction {
     name: String,
-    user: String,
-    identity_file: Option<String>,
-    #[serde(deserialize_with = "decode::string_or_seq")]
-    hosts: Vec<String>,
-    args: Vec<String>, // ssh args
     script: String,
     #[serde(flatten)]
+    ssh: SshOptions,
+    /// How to run across hosts
+    #[serde(default)]
+    strategy: Strategy,
+    /// Number of hosts to run at a time, for rolling strategy
+    batch_size: Option<usize>,
+    /// Number of failed hosts tolerated, the rest hosts are skipped once
+    /// exceeded
+    #[serde(default)]
+    max_failures: usize,
+    /// Patterns of env keys forwarded to remote, all by default
+    #[serde(default = "default_forward_env")]
+    forward_env: GlobSet,
+    /// Patterns of env keys not forwarded to remote, by default git, arrow
+    /// local paths, and those look like secrets
+    #[serde(default = "default_exclude_env")]
+    exclude_env: GlobSet,
+    /// Send env by ssh `SendEnv` option, instead of exporting in script,
+    /// it requires `AcceptEnv` configured on remote
+    #[serde(default)]
+    send_env: bool,
+    /// Which ssh client to connect with
+    #[serde(default)]
+    client: Client,
+    /// Seconds for the script to finish on a host, the host fails once
+    /// exceeded, no limit by default
+    timeout: Option<u64>,
+    #[serde(flatten)]
     envs: Envs,
 }
 
+#[derive(Debug, Deserialize, Clone, Copy, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum Client {
+    /// System `ssh` binary
+    #[default]
+    OpenSsh,
+    /// Embedded client, available if built with feature `native-ssh`.
+    /// `proxy_jump`, `args` and `forward_agent` are not supported.
+    Native,
+}
+
+fn default_forward_env() -> GlobSet {
+    GlobSet::new(&["*".to_string()]).unwrap()
+}
+
+fn default_exclude_env() -> GlobSet {
+    let patterns = [
+        "GIT_*",
+        "ARROW_ENV",
+        "ARROW_WORKSPACE",
+        "*SECRET*",
+        "*TOKEN*",
+        "*PASSWORD*",
+        "*_KEY",
+    ];
+    GlobSet::new(&patterns.map(String::from)).unwrap()
+}
+
+/// Options to connect to remote hosts over ssh
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+pub struct SshOptions {
+    /// Default user, could be overridden by `user@host` in hosts
+    user: Option<String>,
+    /// Entries as `[user@]host[:port]`, IPv6 address in brackets, e.g.
+    /// `deploy@[::1]:2222`
+    #[serde(deserialize_with = "parse_hosts")]
+    #[schemars(schema_with = "decode::string_or_seq_schema")]
+    hosts: Vec<Host>,
+    identity_file: Option<String>,
+    /// Known hosts file, instead of the one of current user
+    known_hosts: Option<String>,
+    /// Policy of checking host key
+    strict_host_key_checking: Option<HostKeyChecking>,
+    /// Jump host(s), as `[user@]host[:port]`, comma separated
+    proxy_jump: Option<String>,
+    /// Timeout in seconds to connect
+    connect_timeout: Option<u32>,
+    /// Forward ssh agent to remote
+    #[serde(default)]
+    forward_agent: bool,
+    /// Extra ssh args
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+enum HostKeyChecking {
+    Yes,
+    No,
+    AcceptNew,
+}
+
+impl HostKeyChecking {
+    fn as_str(&self) -> &'static str {
+        match self {
+            HostKeyChecking::Yes => "yes",
+            HostKeyChecking::No => "no",
+            HostKeyChecking::AcceptNew => "accept-new",
+        }
+    }
+}
+
+/// Host entry parsed from `[user@]host[:port]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Host {
+    /// Entry as given, to identify host in output
+    pub spec: String,
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl Host {
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let (user, rest) = match spec.rsplit_once('@') {
+            Some((user, rest)) => (Some(user.to_string()), rest),
+            None => (None, spec),
+        };
+        let (host, port) = if let Some(bracketed) = rest.strip_prefix('[') {
+            let (host, rest) = bracketed
+                .split_once(']')
+                .ok_or_else(|| anyhow!("Invalid host '{}', missing ']'", spec))?;
+            match rest {
+                "" => (host, None),
+                _ => match rest.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => return Err(anyhow!("Invalid host '{}'", spec)),
+                },
+            }
+        } else {
+            match rest.split_once(':') {
+                // bare IPv6 address without port
+                Some(_) if rest.matches(':').count() > 1 => (rest, None),
+                Some((host, port)) => (host, Some(port)),
+                None => (rest, None),
+            }
+        };
+        if host.is_empty() || user.as_deref() == Some("") {
+            return Err(anyhow!("Invalid host '{}'", spec));
+        }
+        let port = match port {
+            Some(port) => Some(
+                port.parse::<u16>()
+                    .map_err(|_| anyhow!("Invalid port of host '{}'", spec))?,
+            ),
+            None => None,
+        };
+        Ok(Host {
+            spec: spec.to_string(),
+            user,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+fn parse_hosts<'d, D>(deserializer: D) -> Result<Vec<Host>, D::Error>
+where
+    D: Deserializer<'d>,
+{
+    decode::string_or_seq(deserializer)?
+        .iter()
+        .map(|spec| Host::parse(spec).map_err(|err| de::Error::custom(format!("{:#}", err))))
+        .collect()
+}
+
+impl SshOptions {
+    pub fn hosts(&self) -> &[Host] {
+        &self.hosts
+    }
+
+    /// Build ssh options, without destination
+    pub fn ssh_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(ref identity_file) = self.identity_file {
+            args.push("-i".to_string());
+            args.push(identity_file.clone());
+        }
+        if let Some(ref known_hosts) = self.known_hosts {
+            args.push("-o".to_string());
+            args.push(format!("UserKnownHostsFile={}", known_hosts));
+        }
+        if let Some(checking) = self.strict_host_key_checking {
+            args.push("-o".to_string());
+            args.push(format!("StrictHostKeyChecking={}", checking.as_str()));
+        }
+        if let Some(ref proxy_jump) = self.proxy_jump {
+            args.push("-J".to_string());
+            args.push(proxy_jump.clone());
+        }
+        if let Some(timeout) = self.connect_timeout {
+            args.push("-o".to_string());
+            args.push(format!("ConnectTimeout={}", timeout));
+        }
+        if self.forward_agent {
+            args.push("-A".to_string());
+        }
+        args.extend(self.args.clone());
+        args
+    }
+
+    /// User to connect to host as, if specified
+    pub fn host_user<'a>(&'a self, host: &'a Host) -> Option<&'a String> {
+        host.user.as_ref().or(self.user.as_ref())
+    }
+
+    /// Build ssh command line args to connect to host
+    pub fn host_args(&self, host: &Host) -> Vec<String> {
+        let mut args = self.ssh_args();
+        if let Some(port) = host.port {
+            args.push("-p".to_string());
+            args.push(port.to_string());
+        }
+        if let Some(user) = self.host_user(host) {
+            args.push("-l".to_string());
+            args.push(user.clone());
+        }
+        args.push(host.host.clone());
+        args
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum Strategy {
+    /// One host after another
+    #[default]
+    Serial,
+    /// All hosts at once
+    Parallel,
+    /// Hosts in batches of `batch_size`
+    Rolling,
+}
+
+/// Interval to check if ssh process has exited, when timeout is set
+const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Result of running on a host
+struct HostResult {
+    host: String,
+    status: HostStatus,
+    duration: Option<Duration>,
+}
+
+enum HostStatus {
+    Ok,
+    Failed(String),
+    Skipped,
+}
+
 impl IAction for SshAction {
-    fn run(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
+    fn run(&self, _ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
+        println!("### {}\n", self.name);
+        let hosts = self.ssh.hosts();
+        let vars = self.forward_vars(self.envs.inherit(parent_env).build_env()?);
+        let (results, failures) = run_batches(
+            hosts,
+            self.batch_size(hosts.len()),
+            self.max_failures,
+            |host| self.run_timed(host, &vars),
+        );
+        print_summary(&results);
+        if failures > self.max_failures {
+            return Err(anyhow!("{} of {} hosts failed", failures, hosts.len()));
+        }
+        Ok(())
+    }
+
+    fn plan(&self, _ctx: &Context, _parent_env: &Envs) -> anyhow::Result<()> {
         println!("### {}\n", self.name);
-        let vars = self.envs.inherit(parent_env).build_env()?;
-        let env_lines: Vec<String> = vars.iter().map(|(k, v)| format!("{}='{}'", k, v)).collect();
-        let env_sh = env_lines.join("\n");
-        for host in &self.hosts {
-            self.run_on_host(host, &env_sh)?;
+        let vars = self.forward_vars(self.envs.plan_env()?);
+        print_vars(&vars.into_iter().collect());
+        println!(
+            "  strategy: {:?}, batch_size: {}, max_failures: {}",
+            self.strategy,
+            self.batch_size.unwrap_or(1),
+            self.max_failures
+        );
+        for host in self.ssh.hosts() {
+            println!("  $ ssh {} 'sh -s'", self.ssh.host_args(host).join(" "));
+        }
+        for line in self.script.lines() {
+            println!("    {}", line);
         }
         Ok(())
     }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn envs(&self) -> &Envs {
+        &self.envs
+    }
 }
 
 impl SshAction {
-    fn run_on_host(&self, host_port: &String, env_sh: &String) -> anyhow::Result<()> {
-        let (host, port) = host_port.split_once(":").unwrap_or((host_port, "22"));
-        let user_host = format!("{}@{}", self.user, host);
-        println!("ssh -p {} {} 'sh -s'\n", port, user_host);
-        let mut cmd = Command::new("ssh");
-        if let Some(ref identity_file) = self.identity_file {
-            cmd.arg("-i").arg(identity_file);
+    /// Number of hosts to run at a time by strategy
+    fn batch_size(&self, hosts: usize) -> usize {
+        match self.strategy {
+            Strategy::Serial => 1,
+            Strategy::Parallel => hosts,
+            Strategy::Rolling => self.batch_size.unwrap_or(1),
+        }
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+
+    /// Filter env by forward_env and exclude_env, sorted by key. Keys that
+    /// are not valid shell names are dropped.
+    fn forward_vars(&self, vars: HashMap<String, String>) -> Vec<(String, String)> {
+        let mut forwarded: Vec<(String, String)> = vars
+            .into_iter()
+            .filter(|(key, _)| self.forward_env.matches(key) && !self.exclude_env.matches(key))
+            .filter(|(key, _)| {
+                let valid = is_env_name(key);
+                if !valid {
+                    eprintln!("  Warning: env '{}' is not forwarded, invalid name", key);
+                }
+                valid
+            })
+            .collect();
+        forwarded.sort();
+        forwarded
+    }
+
+    fn run_timed(&self, host: &Host, vars: &[(String, String)]) -> HostResult {
+        let start_time = Instant::now();
+        let status = match self.run_on_host(host, vars) {
+            Ok(()) => HostStatus::Ok,
+            Err(err) => {
+                eprintln!("  [{}] Error: {:#}", host.spec, err);
+                HostStatus::Failed(format!("{:#}", err))
+            }
+        };
+        HostResult {
+            host: host.spec.clone(),
+            status,
+            duration: Some(start_time.elapsed()),
+        }
+    }
+
+    fn run_on_host(&self, host: &Host, vars: &[(String, String)]) -> anyhow::Result<()> {
+        match self.client {
+            Client::OpenSsh => self.run_openssh(host, vars),
+            Client::Native => self.run_native(host, vars),
         }
-        for arg in &self.args {
-            cmd.arg(arg);
+    }
+
+    /// Script fed to remote shell, env exported first unless sent by ssh
+    fn remote_input(&self, vars: &[(String, String)]) -> String {
+        let mut input = String::new();
+        if !self.send_env {
+            for (key, value) in vars {
+                input.push_str(&format!("export {}={}\n", key, shell_quote(value)));
+            }
+        }
+        input.push_str(&self.script);
+        input.push('\n');
+        input
+    }
+
+    #[cfg(feature = "native-ssh")]
+    fn run_native(&self, host: &Host, vars: &[(String, String)]) -> anyhow::Result<()> {
+        println!("  [{}] connecting with native client", host.spec);
+        let setenv = match self.send_env {
+            true => vars,
+            false => &[],
+        };
+        native::run_script(
+            &self.ssh,
+            host,
+            setenv,
+            &self.remote_input(vars),
+            self.timeout(),
+        )
+    }
+
+    #[cfg(not(feature = "native-ssh"))]
+    fn run_native(&self, _host: &Host, _vars: &[(String, String)]) -> anyhow::Result<()> {
+        Err(anyhow!(
+            "native ssh client is not available, arrow is built without feature native-ssh"
+        ))
+    }
+
+    fn run_openssh(&self, host: &Host, vars: &[(String, String)]) -> anyhow::Result<()> {
+        let host_port = host.spec.as_str();
+        let mut args = self.ssh.host_args(host);
+        if self.send_env {
+            let send_args = vars.iter().map(|(key, _)| format!("SendEnv={}", key));
+            for arg in send_args {
+                args.insert(0, arg);
+                args.insert(0, "-o".to_string());
+            }
+        }
+        println!("  [{}] ssh {} 'sh -s'", host_port, args.join(" "));
+        let mut cmd = Command::new("ssh");
+        if self.send_env {
+            cmd.envs(vars.iter().map(|(k, v)| (k, v)));
         }
-        cmd.arg("-p").arg(&port);
         let mut child = cmd
-            .arg(&user_host)
+            .args(&args)
             .arg("sh -s") // read commands from stdin
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
             .spawn()?;
-        // source env vars first
-        // then exec user script
-        let childinput = child.stdin.as_mut().unwrap();
-        childinput.write_all(env_sh.as_bytes())?;
-        childinput.write_all(b"\n")?;
-        childinput.flush()?;
-        childinput.write_all(self.script.as_bytes())?;
-        childinput.write_all(b"exit\n")?; // why it dose not exit automatically?
+        let mut childinput = child.stdin.take().unwrap();
+        childinput.write_all(self.remote_input(vars).as_bytes())?;
         childinput.flush()?;
-        if let Some(ref mut stdout) = child.stdout {
-            let reader = BufReader::new(stdout);
-            for line in reader.lines() {
+        // close stdin, so that remote shell exits at the end of script
+        drop(childinput);
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+        let status = thread::scope(|scope| {
+            scope.spawn(|| {
+                for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                    output::print_err(&format!("[{}] {}", host_port, line));
+                }
+            });
+            let waiter = scope.spawn(|| wait_timeout(&mut child, self.timeout()));
+            for line in BufReader::new(stdout).lines() {
                 match line {
-                    Ok(line) => println!("  {}", line),
-                    Err(err) => eprintln!("Error: {}", err),
+                    Ok(line) => output::print_out(&format!("[{}] {}", host_port, line)),
+                    Err(err) => eprintln!("  [{}] Error: {}", host_port, err),
                 }
             }
+            waiter.join().expect("waiter of ssh panicked")
+        })?;
+        if !status.success() {
+            return Err(anyhow!("ssh exited with {}", status));
         }
-        let _ = child.wait()?;
         Ok(())
     }
 }
+
+/// Run hosts in batches, hosts of a batch at the same time. Once failed
+/// hosts exceed max_failures, the rest batches are skipped. Returns results
+/// in order of hosts, and the number of failed hosts.
+fn run_batches<F>(
+    hosts: &[Host],
+    batch_size: usize,
+    max_failures: usize,
+    run: F,
+) -> (Vec<HostResult>, usize)
+where
+    F: Fn(&Host) -> HostResult + Sync,
+{
+    let mut results = Vec::new();
+    let mut failures = 0;
+    for batch in hosts.chunks(batch_size.max(1)) {
+        if failures > max_failures {
+            results.extend(batch.iter().map(|host| HostResult {
+                host: host.spec.clone(),
+                status: HostStatus::Skipped,
+                duration: None,
+            }));
+            continue;
+        }
+        let batch_results: Vec<HostResult> = thread::scope(|scope| {
+            let handles: Vec<_> = batch.iter().map(|host| scope.spawn(|| run(host))).collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("ssh thread panicked"))
+                .collect()
+        });
+        failures += batch_results
+            .iter()
+            .filter(|result| matches!(result.status, HostStatus::Failed(_)))
+            .count();
+        results.extend(batch_results);
+    }
+    (results, failures)
+}
+
+/// Wait for child to exit, it is killed once timeout is exceeded
+fn wait_timeout(child: &mut Child, timeout: Option<Duration>) -> anyhow::Result<ExitStatus> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(child.wait()?),
+    };
+    let start_time = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if start_time.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Err(anyhow!("timed out after {}s", timeout.as_secs()));
+        }
+        thread::sleep(WAIT_INTERVAL);
+    }
+}
+
+/// Print summary table of results by host
+fn print_summary(results: &[HostResult]) {
+    println!();
+    for line in summary_lines(results) {
+        println!("{}", line);
+    }
+}
+
+/// Rows of summary table, with header
+fn summary_lines(results: &[HostResult]) -> Vec<String> {
+    let width = results
+        .iter()
+        .map(|result| result.host.len())
+        .max()
+        .unwrap_or(0)
+        .max("HOST".len());
+    let mut lines = vec![format!(
+        "  {:width$}  {:8}  {:8}  ERROR",
+        "HOST", "STATUS", "DURATION"
+    )];
+    for result in results {
+        let (status, error) = match &result.status {
+            HostStatus::Ok => ("ok", ""),
+            HostStatus::Failed(err) => ("failed", err.as_str()),
+            HostStatus::Skipped => ("skipped", ""),
+        };
+        let duration = match result.duration {
+            Some(duration) => format_duration(duration),
+            None => "-".to_string(),
+        };
+        let row = format!(
+            "  {:width$}  {:8}  {:8}  {}",
+            result.host, status, duration, error
+        );
+        lines.push(row.trim_end().to_string());
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    fn hosts(specs: &[&str]) -> Vec<Host> {
+        specs
+            .iter()
+            .map(|spec| Host::parse(spec).unwrap())
+            .collect()
+    }
+
+    fn result(host: &str, status: HostStatus, duration: Option<u64>) -> HostResult {
+        HostResult {
+            host: host.to_string(),
+            status,
+            duration: duration.map(Duration::from_millis),
+        }
+    }
+
+    /// Run batches of hosts, those in `failing` fail. Returns statuses by
+    /// host, failures, the max number of hosts run at a time, and hosts in
+    /// order of run
+    fn run_hosts(
+        batch_size: usize,
+        max_failures: usize,
+        failing: &[&str],
+    ) -> (Vec<String>, usize, usize, Vec<String>) {
+        let hosts = hosts(&["a", "b", "c", "d", "e"]);
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let started = Mutex::new(Vec::new());
+        let (results, failures) = run_batches(&hosts, batch_size, max_failures, |host| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now, Ordering::SeqCst);
+            started.lock().unwrap().push(host.spec.clone());
+            thread::sleep(Duration::from_millis(50));
+            running.fetch_sub(1, Ordering::SeqCst);
+            let status = match failing.contains(&host.spec.as_str()) {
+                true => HostStatus::Failed("exited".to_string()),
+                false => HostStatus::Ok,
+            };
+            result(&host.spec, status, Some(1))
+        });
+        let statuses = results
+            .iter()
+            .map(|result| {
+                let status = match result.status {
+                    HostStatus::Ok => "ok",
+                    HostStatus::Failed(_) => "failed",
+                    HostStatus::Skipped => "skipped",
+                };
+                format!("{}:{}", result.host, status)
+            })
+            .collect();
+        let started = started.into_inner().unwrap();
+        (statuses, failures, max_running.into_inner(), started)
+    }
+
+    fn ssh_action(yaml: &str) -> SshAction {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn remote_input_keeps_values() {
+        let values = [
+            ("QUOTE", "it's"),
+            ("SUBST", "$(touch pwned) `id` $HOME"),
+            ("SPACES", "  a  b  "),
+            ("LINES", "first\nsecond\n"),
+            ("EMPTY", ""),
+        ];
+        let mut action = ssh_action("{name: s, hosts: [a], script: x}");
+        action.script =
+            r#"printf '%s\0' "$QUOTE" "$SUBST" "$SPACES" "$LINES" "$EMPTY""#.to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let forwarded = action.forward_vars(vars(&values));
+        assert_eq!(forwarded.len(), values.len());
+        let mut child = Command::new("sh")
+            .arg("-s")
+            .current_dir(dir.path())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let input = action.remote_input(&forwarded);
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "{}", input);
+        let printed: Vec<String> = String::from_utf8(output.stdout)
+            .unwrap()
+            .split_terminator('\0')
+            .map(String::from)
+            .collect();
+        let expected: Vec<&str> = values.iter().map(|(_, v)| *v).collect();
+        assert_eq!(printed, expected);
+        assert!(!dir.path().join("pwned").exists());
+    }
+
+    #[test]
+    fn remote_input_without_exports_for_send_env() {
+        let action = ssh_action("{name: s, hosts: [a], script: 'echo $A', send_env: true}");
+        let input = action.remote_input(&[("A".to_string(), "1".to_string())]);
+        assert_eq!(input, "echo $A\n");
+
+        let action = ssh_action("{name: s, hosts: [a], script: 'echo $A'}");
+        let input = action.remote_input(&[("A".to_string(), "1".to_string())]);
+        assert_eq!(input, "export A='1'\necho $A\n");
+    }
+
+    #[test]
+    fn forward_vars_by_patterns() {
+        let all = vars(&[
+            ("BRANCH", "main"),
+            ("GIT_DIR", "/srv/git/app.git"),
+            ("ARROW_ENV", "/tmp/env"),
+            ("GITHUB_TOKEN", "t"),
+            ("DEPLOY_KEY", "k"),
+            ("DB_PASSWORD", "p"),
+            ("APP_SECRET", "s"),
+            ("PUSH_OPTION_A-B", "1"),
+            ("1ST", "1"),
+            ("REV_SHORT", "abc"),
+        ]);
+        let action = ssh_action("{name: s, hosts: [a], script: x}");
+        let keys: Vec<String> = action
+            .forward_vars(all.clone())
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(keys, ["BRANCH", "REV_SHORT"]);
+
+        let action = ssh_action(
+            "{name: s, hosts: [a], script: x, forward_env: ['REV_*', 'GIT_*'], exclude_env: []}",
+        );
+        let forwarded = action.forward_vars(all);
+        assert_eq!(
+            forwarded,
+            [
+                ("GIT_DIR".to_string(), "/srv/git/app.git".to_string()),
+                ("REV_SHORT".to_string(), "abc".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_hosts() {
+        let cases: &[(&str, Option<&str>, &str, Option<u16>)] = &[
+            ("web1", None, "web1", None),
+            ("host:22", None, "host", Some(22)),
+            ("deploy@web1", Some("deploy"), "web1", None),
+            ("deploy@[::1]:2222", Some("deploy"), "::1", Some(2222)),
+            ("[::1]", None, "::1", None),
+            ("::1", None, "::1", None),
+            ("fe80::1:22", None, "fe80::1:22", None),
+            ("a@b@c", Some("a@b"), "c", None),
+        ];
+        for (spec, user, host, port) in cases {
+            let parsed = Host::parse(spec).unwrap();
+            assert_eq!(
+                (parsed.user.as_deref(), parsed.host.as_str(), parsed.port),
+                (*user, *host, *port),
+                "{}",
+                spec
+            );
+            assert_eq!(parsed.spec, *spec);
+        }
+    }
+
+    #[test]
+    fn parse_invalid_hosts() {
+        let cases = [
+            ("[::1", "Invalid host '[::1', missing ']'"),
+            (
+                "deploy@[::1:22",
+                "Invalid host 'deploy@[::1:22', missing ']'",
+            ),
+            ("[::1]22", "Invalid host '[::1]22'"),
+            ("host:ssh", "Invalid port of host 'host:ssh'"),
+            ("host:70000", "Invalid port of host 'host:70000'"),
+            ("host:", "Invalid port of host 'host:'"),
+            ("[::1]:", "Invalid port of host '[::1]:'"),
+            ("", "Invalid host ''"),
+            ("deploy@", "Invalid host 'deploy@'"),
+            ("@web1", "Invalid host '@web1'"),
+            (":22", "Invalid host ':22'"),
+            ("[]:22", "Invalid host '[]:22'"),
+        ];
+        for (spec, message) in cases {
+            let err = Host::parse(spec).unwrap_err();
+            assert_eq!(err.to_string(), message, "{}", spec);
+        }
+    }
+
+    fn ssh_options(yaml: &str) -> SshOptions {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn host_args_of_options() {
+        let options = ssh_options("{hosts: [web1, 'root@[::1]:2222']}");
+        assert!(options.ssh_args().is_empty());
+        assert_eq!(options.host_args(&options.hosts()[0]), ["web1"]);
+        assert_eq!(
+            options.host_args(&options.hosts()[1]),
+            ["-p", "2222", "-l", "root", "::1"]
+        );
+
+        let options = ssh_options(
+            "{user: deploy, hosts: [web1, 'root@web2:22'], identity_file: /keys/id, \
+             known_hosts: /keys/known_hosts, strict_host_key_checking: accept-new, \
+             proxy_jump: 'jump@bastion:2222', connect_timeout: 5, forward_agent: true, \
+             args: [-v]}",
+        );
+        let ssh_args = [
+            "-i",
+            "/keys/id",
+            "-o",
+            "UserKnownHostsFile=/keys/known_hosts",
+            "-o",
+            "StrictHostKeyChecking=accept-new",
+            "-J",
+            "jump@bastion:2222",
+            "-o",
+            "ConnectTimeout=5",
+            "-A",
+            "-v",
+        ];
+        assert_eq!(options.ssh_args(), ssh_args);
+        assert_eq!(
+            options.host_args(&options.hosts()[0]),
+            [&ssh_args[..], &["-l", "deploy", "web1"]].concat()
+        );
+        assert_eq!(
+            options.host_args(&options.hosts()[1]),
+            [&ssh_args[..], &["-p", "22", "-l", "root", "web2"]].concat()
+        );
+
+        for (checking, value) in [("yes", "yes"), ("no", "no")] {
+            let options = ssh_options(&format!(
+                "{{hosts: a, strict_host_key_checking: {}}}",
+                checking
+            ));
+            assert_eq!(
+                options.ssh_args(),
+                ["-o".to_string(), format!("StrictHostKeyChecking={}", value)]
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_host_in_options() {
+        let err = serde_yaml::from_str::<SshOptions>("{hosts: [web1, 'web2:x']}").unwrap_err();
+        assert!(
+            err.to_string().contains("Invalid port of host 'web2:x'"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn batch_size_of_strategy() {
+        let action =
+            |yaml: &str| ssh_action(&format!("{{name: s, script: x, hosts: [a], {}}}", yaml));
+        assert_eq!(action("strategy: serial").batch_size(5), 1);
+        assert_eq!(action("strategy: parallel").batch_size(5), 5);
+        assert_eq!(action("strategy: rolling").batch_size(5), 1);
+        assert_eq!(action("strategy: rolling, batch_size: 2").batch_size(5), 2);
+    }
+
+    #[test]
+    fn run_in_batches() {
+        let (statuses, failures, max_running, started) = run_hosts(1, 0, &[]);
+        assert_eq!(statuses, ["a:ok", "b:ok", "c:ok", "d:ok", "e:ok"]);
+        assert_eq!((failures, max_running), (0, 1));
+        assert_eq!(started, ["a", "b", "c", "d", "e"]);
+
+        let (statuses, failures, max_running, _) = run_hosts(2, 0, &[]);
+        assert_eq!(statuses.len(), 5);
+        assert_eq!((failures, max_running), (0, 2));
+
+        let (_, failures, max_running, _) = run_hosts(5, 0, &["b", "d"]);
+        assert_eq!((failures, max_running), (2, 5));
+    }
+
+    #[test]
+    fn skip_rest_once_max_failures_exceeded() {
+        let (statuses, failures, _, started) = run_hosts(1, 0, &["b"]);
+        assert_eq!(
+            statuses,
+            ["a:ok", "b:failed", "c:skipped", "d:skipped", "e:skipped"]
+        );
+        assert_eq!(failures, 1);
+        assert_eq!(started, ["a", "b"]);
+
+        // failures within max_failures go on
+        let (statuses, failures, _, _) = run_hosts(1, 1, &["b"]);
+        assert_eq!(statuses, ["a:ok", "b:failed", "c:ok", "d:ok", "e:ok"]);
+        assert_eq!(failures, 1);
+
+        // the batch of failed host is finished
+        let (statuses, failures, _, _) = run_hosts(2, 0, &["a"]);
+        assert_eq!(
+            statuses,
+            ["a:failed", "b:ok", "c:skipped", "d:skipped", "e:skipped"]
+        );
+        assert_eq!(failures, 1);
+    }
+
+    #[test]
+    fn summary_of_results() {
+        let results = [
+            result("web1", HostStatus::Ok, Some(1500)),
+            result(
+                "deploy@[::1]:2222",
+                HostStatus::Failed("ssh exited with exit status: 1".to_string()),
+                Some(20),
+            ),
+            result("web3", HostStatus::Skipped, None),
+        ];
+        assert_eq!(
+            summary_lines(&results),
+            [
+                "  HOST               STATUS    DURATION  ERROR",
+                "  web1               ok        1s",
+                "  deploy@[::1]:2222  failed    20ms      ssh exited with exit status: 1",
+                "  web3               skipped   -",
+            ]
+        );
+        assert_eq!(summary_lines(&[]), ["  HOST  STATUS    DURATION  ERROR"]);
+    }
+
+    #[test]
+    fn wait_timeout_returns_status_in_time() {
+        let mut child = Command::new("sh").arg("-c").arg("exit 3").spawn().unwrap();
+        let status = wait_timeout(&mut child, Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(status.code(), Some(3));
+    }
+
+    #[test]
+    fn wait_timeout_kills_child() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let start_time = Instant::now();
+        let err = wait_timeout(&mut child, Some(Duration::from_millis(200))).unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+        assert!(start_time.elapsed() < Duration::from_secs(5));
+        assert!(child.try_wait().unwrap().is_some());
+    }
+}
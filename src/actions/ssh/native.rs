@@ -0,0 +1,457 @@
+use super::{Host, HostKeyChecking, SshOptions};
+use crate::output;
+use anyhow::{anyhow, Context as _};
+use ssh2::{CheckResult, KnownHostFileKind, Session};
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_PORT: u16 = 22;
+/// Interval to poll output of remote process
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Timeout of blocking calls of session, unless `connect_timeout` is set
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Run script on host with embedded ssh client, env is set by `setenv` if
+/// given, which requires `AcceptEnv` on remote as `SendEnv` does. The
+/// channel is closed once the script runs longer than timeout.
+pub fn run_script(
+    options: &SshOptions,
+    host: &Host,
+    setenv: &[(String, String)],
+    input: &str,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    let session = connect(options, host)?;
+    let mut channel = session.channel_session()?;
+    for (key, value) in setenv {
+        channel
+            .setenv(key, value)
+            .with_context(|| format!("Failed to set env {}", key))?;
+    }
+    channel.exec("sh -s")?;
+    channel.write_all(input.as_bytes())?;
+    channel.flush()?;
+    // remote shell exits at the end of script
+    channel.send_eof()?;
+
+    // poll stdout and stderr, so that neither blocks the other
+    session.set_blocking(false);
+    let mut stderr = channel.stderr();
+    let mut out_lines = LineBuffer::default();
+    let mut err_lines = LineBuffer::default();
+    let mut buf = [0u8; 8192];
+    let start_time = Instant::now();
+    loop {
+        let mut progressed = false;
+        match channel.read(&mut buf) {
+            Ok(0) => {}
+            Ok(n) => {
+                progressed = true;
+                out_lines.push(&buf[..n], |line| {
+                    output::print_out(&format!("[{}] {}", host.spec, line))
+                });
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err.into()),
+        }
+        match stderr.read(&mut buf) {
+            Ok(0) => {}
+            Ok(n) => {
+                progressed = true;
+                err_lines.push(&buf[..n], |line| {
+                    output::print_err(&format!("[{}] {}", host.spec, line))
+                });
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err.into()),
+        }
+        if !progressed {
+            if channel.eof() {
+                break;
+            }
+            if let Some(timeout) = timeout.filter(|timeout| start_time.elapsed() >= *timeout) {
+                session.set_blocking(true);
+                // best effort, the session is dropped anyway
+                let _ = channel.close();
+                return Err(anyhow!("timed out after {}s", timeout.as_secs()));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+    out_lines.finish(|line| output::print_out(&format!("[{}] {}", host.spec, line)));
+    err_lines.finish(|line| output::print_err(&format!("[{}] {}", host.spec, line)));
+    session.set_blocking(true);
+
+    channel.wait_close()?;
+    if let Some(signal) = channel.exit_signal()?.exit_signal {
+        return Err(anyhow!("remote process killed by signal {}", signal));
+    }
+    let code = channel.exit_status()?;
+    if code != 0 {
+        return Err(anyhow!("remote script exited with code {}", code));
+    }
+    Ok(())
+}
+
+/// Connect to host, verify its key and authenticate
+fn connect(options: &SshOptions, host: &Host) -> anyhow::Result<Session> {
+    // libssh2 requests agent forwarding, but does not serve the agent
+    // channels opened by remote
+    if options.proxy_jump.is_some() || !options.args.is_empty() || options.forward_agent {
+        return Err(anyhow!(
+            "proxy_jump, args and forward_agent are not supported by native ssh client"
+        ));
+    }
+    let port = host.port.unwrap_or(DEFAULT_PORT);
+    let timeout = options
+        .connect_timeout
+        .map(|secs| Duration::from_secs(secs.into()));
+    let mut last_err = None;
+    let mut tcp = None;
+    for addr in (host.host.as_str(), port).to_socket_addrs()? {
+        let result = match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+        match result {
+            Ok(stream) => {
+                tcp = Some(stream);
+                break;
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    let tcp = match (tcp, last_err) {
+        (Some(tcp), _) => tcp,
+        (None, Some(err)) => return Err(err).context("Failed to connect"),
+        (None, None) => return Err(anyhow!("No address resolved for {}", host.host)),
+    };
+
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tcp);
+    // also bounds blocking calls after connected, as output is polled
+    session.set_timeout(timeout.unwrap_or(IO_TIMEOUT).as_millis() as u32);
+    session.handshake().context("Failed ssh handshake")?;
+    verify_host_key(options, &session, host, port)?;
+
+    let user = match options.host_user(host) {
+        Some(user) => user.clone(),
+        None => env::var("USER")
+            .or_else(|_| env::var("LOGNAME"))
+            .context("No user specified")?,
+    };
+    match options.identity_file {
+        Some(ref identity_file) => {
+            session.userauth_pubkey_file(&user, None, &PathBuf::from(identity_file), None)
+        }
+        None => session.userauth_agent(&user),
+    }
+    .with_context(|| format!("Failed to authenticate as {}", user))?;
+    if !session.authenticated() {
+        return Err(anyhow!("Failed to authenticate as {}", user));
+    }
+    Ok(session)
+}
+
+/// Check host key against known hosts file per `strict_host_key_checking`,
+/// which is `yes` by default as no one is there to confirm
+fn verify_host_key(
+    options: &SshOptions,
+    session: &Session,
+    host: &Host,
+    port: u16,
+) -> anyhow::Result<()> {
+    let checking = options
+        .strict_host_key_checking
+        .unwrap_or(HostKeyChecking::Yes);
+    if matches!(checking, HostKeyChecking::No) {
+        return Ok(());
+    }
+    let path = match options.known_hosts {
+        Some(ref path) => PathBuf::from(path),
+        None => dirs::home_dir()
+            .ok_or_else(|| anyhow!("No home dir to find known_hosts"))?
+            .join(".ssh/known_hosts"),
+    };
+    let (key, key_type) = session
+        .host_key()
+        .ok_or_else(|| anyhow!("No host key of {}", host.host))?;
+    let mut known_hosts = session.known_hosts()?;
+    if path.exists() {
+        known_hosts
+            .read_file(&path, KnownHostFileKind::OpenSSH)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+    }
+    match known_hosts.check_port(&host.host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(anyhow!(
+            "Host key of {} does not match the one in {}",
+            host.host,
+            path.display()
+        )),
+        CheckResult::NotFound if matches!(checking, HostKeyChecking::AcceptNew) => {
+            let name = match port {
+                DEFAULT_PORT => host.host.clone(),
+                _ => format!("[{}]:{}", host.host, port),
+            };
+            known_hosts.add(&name, key, "", key_type.into())?;
+            let added = known_hosts
+                .hosts()?
+                .into_iter()
+                .last()
+                .ok_or_else(|| anyhow!("Failed to add host key of {}", host.host))?;
+            let line = known_hosts.write_string(&added, KnownHostFileKind::OpenSSH)?;
+            let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+            writeln!(file, "{}", line.trim_end())?;
+            Ok(())
+        }
+        CheckResult::NotFound => Err(anyhow!(
+            "Host key of {} is not found in {}",
+            host.host,
+            path.display()
+        )),
+        CheckResult::Failure => Err(anyhow!("Failed to check host key of {}", host.host)),
+    }
+}
+
+/// Split output into lines, which may arrive in pieces
+#[derive(Default)]
+struct LineBuffer {
+    pending: Vec<u8>,
+}
+
+impl LineBuffer {
+    fn push(&mut self, data: &[u8], mut print: impl FnMut(&str)) {
+        self.pending.extend_from_slice(data);
+        while let Some(pos) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=pos).collect();
+            print(String::from_utf8_lossy(&line[..pos]).trim_end_matches('\r'));
+        }
+    }
+
+    fn finish(&mut self, mut print: impl FnMut(&str)) {
+        if !self.pending.is_empty() {
+            print(&String::from_utf8_lossy(&self.pending));
+            self.pending.clear();
+        }
+    }
+}
+
+/// Tests against a local sshd, which is started from `$ARROW_TEST_SSHD` or
+/// `/usr/sbin/sshd`. They are ignored by default, run them with
+/// `cargo test --features native-ssh -- --ignored`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::{getuid, User};
+    use std::fs;
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::process::{Child, Command, Stdio};
+    use tempfile::TempDir;
+
+    struct Sshd {
+        dir: TempDir,
+        port: u16,
+        process: Child,
+    }
+
+    impl Drop for Sshd {
+        fn drop(&mut self) {
+            let _ = self.process.kill();
+            let _ = self.process.wait();
+        }
+    }
+
+    fn keygen(path: &Path) {
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+            .arg(path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /// Start sshd on a free port of localhost, which accepts the user key
+    /// and env of `ARROW_*`
+    fn start_sshd() -> Sshd {
+        let sshd = env::var("ARROW_TEST_SSHD").unwrap_or_else(|_| "/usr/sbin/sshd".to_string());
+        assert!(Path::new(&sshd).is_file(), "{} not found", sshd);
+        let dir = TempDir::new().unwrap();
+        let path = dir.path();
+        keygen(&path.join("host_key"));
+        keygen(&path.join("id_ed25519"));
+        fs::copy(path.join("id_ed25519.pub"), path.join("authorized_keys")).unwrap();
+        let port = free_port();
+        let config = format!(
+            "Port {port}\n\
+             ListenAddress 127.0.0.1\n\
+             HostKey {dir}/host_key\n\
+             PidFile {dir}/sshd.pid\n\
+             AuthorizedKeysFile {dir}/authorized_keys\n\
+             StrictModes no\n\
+             UsePAM no\n\
+             PasswordAuthentication no\n\
+             PermitRootLogin prohibit-password\n\
+             AcceptEnv ARROW_*\n",
+            port = port,
+            dir = path.display()
+        );
+        fs::write(path.join("sshd_config"), config).unwrap();
+        let log = fs::File::create(path.join("sshd.log")).unwrap();
+        let process = Command::new(&sshd)
+            .arg("-D")
+            .arg("-e")
+            .arg("-f")
+            .arg(path.join("sshd_config"))
+            .stderr(Stdio::from(log))
+            .spawn()
+            .unwrap();
+        let mut sshd = Sshd { dir, port, process };
+        for _ in 0..100 {
+            if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                return sshd;
+            }
+            if sshd.process.try_wait().unwrap().is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        let log = fs::read_to_string(sshd.dir.path().join("sshd.log")).unwrap_or_default();
+        panic!("sshd is not listening on {}: {}", port, log);
+    }
+
+    impl Sshd {
+        fn host_line(&self) -> String {
+            let key = fs::read_to_string(self.dir.path().join("host_key.pub")).unwrap();
+            let mut fields = key.split_whitespace();
+            format!(
+                "[127.0.0.1]:{} {} {}\n",
+                self.port,
+                fields.next().unwrap(),
+                fields.next().unwrap()
+            )
+        }
+
+        fn options(&self, extra: &str) -> SshOptions {
+            let user = User::from_uid(getuid()).unwrap().unwrap().name;
+            let yaml = format!(
+                "{{hosts: ['127.0.0.1:{}'], user: '{}', identity_file: '{}', known_hosts: '{}', connect_timeout: 5{}}}",
+                self.port,
+                user,
+                self.dir.path().join("id_ed25519").display(),
+                self.dir.path().join("known_hosts").display(),
+                extra
+            );
+            serde_yaml::from_str(&yaml).unwrap()
+        }
+
+        fn trust_host(&self) {
+            fs::write(self.dir.path().join("known_hosts"), self.host_line()).unwrap();
+        }
+
+        fn run(
+            &self,
+            options: &SshOptions,
+            input: &str,
+            timeout: Option<Duration>,
+        ) -> anyhow::Result<()> {
+            run_script(options, &options.hosts()[0], &[], input, timeout)
+        }
+    }
+
+    #[test]
+    fn unsupported_options() {
+        for extra in ["proxy_jump: bastion", "args: [-v]", "forward_agent: true"] {
+            let options: SshOptions =
+                serde_yaml::from_str(&format!("{{hosts: '127.0.0.1:1', {}}}", extra)).unwrap();
+            let err = run_script(&options, &options.hosts()[0], &[], "true\n", None).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "proxy_jump, args and forward_agent are not supported by native ssh client"
+            );
+        }
+    }
+
+    #[test]
+    #[ignore = "needs sshd"]
+    fn run_script_on_sshd() {
+        let sshd = start_sshd();
+        sshd.trust_host();
+        let options = sshd.options("");
+        let marker = sshd.dir.path().join("marker");
+        let script = format!(
+            "export ARROW_X=1\necho \"$ARROW_X\" > '{}'\n",
+            marker.display()
+        );
+        sshd.run(&options, &script, None).unwrap();
+        assert_eq!(fs::read_to_string(&marker).unwrap(), "1\n");
+
+        let err = sshd.run(&options, "exit 3\n", None).unwrap_err();
+        assert!(err.to_string().contains("exited with code 3"));
+    }
+
+    #[test]
+    #[ignore = "needs sshd"]
+    fn setenv_on_sshd() {
+        let sshd = start_sshd();
+        sshd.trust_host();
+        let options = sshd.options("");
+        let marker = sshd.dir.path().join("marker");
+        let script = format!("echo \"$ARROW_SENT\" > '{}'\n", marker.display());
+        let setenv = vec![("ARROW_SENT".to_string(), "a b".to_string())];
+        run_script(&options, &options.hosts()[0], &setenv, &script, None).unwrap();
+        assert_eq!(fs::read_to_string(&marker).unwrap(), "a b\n");
+    }
+
+    #[test]
+    #[ignore = "needs sshd"]
+    fn timeout_on_sshd() {
+        let sshd = start_sshd();
+        sshd.trust_host();
+        let options = sshd.options("");
+        let start_time = Instant::now();
+        let err = sshd
+            .run(&options, "sleep 30\n", Some(Duration::from_secs(1)))
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out after 1s"));
+        assert!(start_time.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    #[ignore = "needs sshd"]
+    fn host_key_checking_on_sshd() {
+        let sshd = start_sshd();
+        let known_hosts = sshd.dir.path().join("known_hosts");
+        let err = sshd.run(&sshd.options(""), "true\n", None).unwrap_err();
+        assert!(err.to_string().contains("is not found in"));
+
+        let options = sshd.options(", strict_host_key_checking: accept-new");
+        sshd.run(&options, "true\n", None).unwrap();
+        let added = fs::read_to_string(&known_hosts).unwrap();
+        assert!(added.starts_with(&format!("[127.0.0.1]:{} ", sshd.port)));
+        // accepted key is checked next time
+        sshd.run(&sshd.options(""), "true\n", None).unwrap();
+
+        // another key of the same host
+        let other = sshd.dir.path().join("other_key");
+        keygen(&other);
+        let key = fs::read_to_string(other.with_extension("pub")).unwrap();
+        fs::write(&known_hosts, format!("[127.0.0.1]:{} {}", sshd.port, key)).unwrap();
+        let err = sshd.run(&sshd.options(""), "true\n", None).unwrap_err();
+        assert!(err.to_string().contains("does not match"));
+    }
+}
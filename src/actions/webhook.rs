@@ -1,37 +1,76 @@
+mod auth;
+mod body;
+mod expect;
+mod tls;
+mod wait;
+
 use crate::actions::IAction;
 use crate::envs::Envs;
-use crate::helper::format_duration;
+use crate::helper::{format_duration, print_vars};
 use crate::repo::Context;
+use anyhow::anyhow;
 use core::time;
 use handlebars::Handlebars;
+use schemars::JsonSchema;
 use serde::Deserialize;
-use serde_urlencoded;
-use serde_yaml::Value;
+use serde_json::Value;
 use std::collections::HashMap;
+use std::path::Path;
 use std::time::Instant;
-use ureq::{self, Response};
+use ureq::{self, Agent, Response};
+
+use auth::Auth;
+use body::{BodyData, Payload};
+use expect::{extract_outputs, Expect, Query};
+use wait::WaitFor;
+
+pub use tls::TlsOptions;
 
-#[derive(Debug, Deserialize, Clone)]
+/// Send http request to webhook
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct WebHookAction {
     name: String,
-    http: HookSpec,
+    http: Box<HookSpec>,
 
     #[serde(flatten)]
     envs: Envs,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct HookSpec {
+/// Http request spec, values are rendered as handlebars templates
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct HookSpec {
     #[serde(default = "default_method")]
     method: String,
     url: String,
+    #[serde(default)]
     headers: HashMap<String, String>,
+    auth: Option<Auth>,
     timeout: Option<time::Duration>,
+    /// Proxy url, by default per `HTTPS_PROXY`, `HTTP_PROXY`, `ALL_PROXY`
+    /// and `NO_PROXY` of env
+    proxy: Option<String>,
+    #[serde(default)]
+    tls: TlsOptions,
     body: Option<BodyData>,
+    /// File in workspace to send as body, instead of `body`
+    body_file: Option<String>,
+    /// Expectations on response
+    #[serde(default)]
+    expect: Expect,
+    /// Env keys and JSON paths of response body to extract them from,
+    /// exported to later actions
+    #[serde(default, deserialize_with = "expect::outputs")]
+    #[schemars(with = "HashMap<String, String>")]
+    outputs: Vec<(Query, String)>,
+    /// Poll url after the request, until the remote job is done
+    wait_for: Option<WaitFor>,
 
-    // env rendered body string
-    #[serde(skip_deserializing)]
-    body_string: String,
+    // env rendered body
+    #[serde(skip)]
+    #[schemars(skip)]
+    payload: Option<Payload>,
 }
 
 fn default_method() -> String {
@@ -44,63 +83,189 @@ impl IAction for WebHookAction {
     fn run(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
         println!("### {}\n", self.name);
         let envs = self.envs.inherit(parent_env);
-        let hook = self.http.render_env(envs)?;
+        let vars = envs.build_env()?;
+        let hook = self.http.render_env(envs.clone())?;
 
-        let method = hook.method.to_uppercase();
-        let url = hook.url.as_ref();
-        println!("  {} {}", method, url);
-        let mut req = ureq::request(method.as_str(), url);
-        let timeout = hook.timeout.unwrap_or(time::Duration::from_secs(10));
-        req = req.timeout(timeout);
-        req = req.set("User-Agent", USER_AGENT);
-        for (k, v) in hook.headers.clone() {
-            req = req.set(&k, &v);
+        let agent = hook.agent(&ctx.workdir())?;
+        let resp_body = hook.execute(&agent, &ctx.workdir())?;
+        write_outputs(&vars, &hook.outputs, &resp_body)?;
+
+        if let Some(ref wait_for) = hook.wait_for {
+            // url may refer to outputs of the request
+            let vars = envs.build_env()?;
+            let wait_for = wait_for.render_env(&handlebars(), &vars)?;
+            let body =
+                wait_for.poll(|| hook.send(&agent, wait_for.method(), wait_for.url(), None))?;
+            write_outputs(&vars, &wait_for.outputs, &body)?;
         }
-        let start_time = Instant::now();
+        Ok(())
+    }
 
-        let resp: Response;
-        // send request depend on body type
-        match &hook.body {
-            None => {
-                resp = req.call()?;
-            }
-            Some(body) => match body {
-                BodyData::FormData(_) => {
-                    req = req.set("Content-Type", "application/x-www-form-urlencoded");
-                    resp = req.send_bytes(&hook.body_string.as_bytes())?;
-                }
-                BodyData::JsonData(_) => {
-                    req = req.set("Content-Type", "application/json");
-                    resp = req.send_bytes(&hook.body_string.as_bytes())?;
-                }
-            },
+    fn plan(&self, _ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
+        println!("### {}\n", self.name);
+        print_vars(&self.envs.plan_env()?);
+        // rendered with masked values, as url, headers and body may have
+        // secrets
+        let envs = self.envs.inherit(parent_env).masked()?;
+        let hook = self.http.render_env(envs)?;
+        println!("  {} {}", hook.method.to_uppercase(), hook.url);
+        for (k, v) in &hook.headers {
+            println!("  {}: {}", k, v);
         }
-        let status = resp.status();
-        let duration = format_duration(start_time.elapsed());
-        let resp_body = resp.into_string()?;
-        println!("  {} ({}): {}", status, duration, resp_body);
-        if status >= 400 {
-            return Err(anyhow::anyhow!("{}: {}", status, resp_body));
+        if let Some(ref auth) = hook.auth {
+            println!("  {}", auth.masked_header());
         }
-        return Ok(());
+        if let Some(ref proxy) = hook.proxy {
+            println!("  proxy: {}", proxy);
+        }
+        hook.tls.plan();
+        if let Some(ref payload) = hook.payload {
+            payload.plan();
+        }
+        if let Some(ref wait_for) = hook.wait_for {
+            println!();
+            wait_for.plan();
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn envs(&self) -> &Envs {
+        &self.envs
     }
 }
 
-type FormData = HashMap<String, String>;
-type JsonData = HashMap<String, Value>;
+/// Write outputs extracted from response body to output env
+fn write_outputs(
+    vars: &HashMap<String, String>,
+    outputs: &[(Query, String)],
+    body: &str,
+) -> anyhow::Result<()> {
+    if outputs.is_empty() {
+        return Ok(());
+    }
+    let outputs = extract_outputs(outputs, body)?;
+    for (key, value) in &outputs {
+        println!("  output {}={}", key, value);
+    }
+    Envs::write_output(vars, &outputs)
+}
 
-#[derive(Debug, Deserialize, Clone)]
-enum BodyData {
-    #[serde(rename = "formData")]
-    FormData(FormData),
-    #[serde(rename = "jsonData")]
-    JsonData(JsonData),
+pub fn handlebars() -> Handlebars<'static> {
+    let mut hbs = Handlebars::new();
+    // values are not html, e.g. `&` in commit message is kept as is
+    hbs.register_escape_fn(handlebars::no_escape);
+    hbs
 }
 
 impl HookSpec {
+    /// Request with JSON body for runners built on webhook, the body is
+    /// sent as is, without rendering
+    pub fn json(method: &str, url: String, body: &Value) -> anyhow::Result<Self> {
+        Ok(HookSpec {
+            method: method.to_string(),
+            url,
+            headers: HashMap::new(),
+            auth: None,
+            timeout: None,
+            proxy: None,
+            tls: TlsOptions::default(),
+            body: None,
+            body_file: None,
+            expect: Expect::default(),
+            outputs: Vec::new(),
+            wait_for: None,
+            payload: Some(Payload::Text {
+                content_type: "application/json".to_string(),
+                data: serde_json::to_string(body)?,
+            }),
+        })
+    }
+
+    pub fn with_header(mut self, key: &str, value: String) -> Self {
+        self.headers.insert(key.to_string(), value);
+        self
+    }
+
+    /// Set proxy and tls options, rendered with variables. Proxy is taken
+    /// from env if not specified.
+    pub fn with_transport(
+        mut self,
+        proxy: Option<&String>,
+        tls: &TlsOptions,
+        variables: &HashMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        let hbs = handlebars();
+        self.proxy = match proxy {
+            Some(proxy) => Some(hbs.render_template(proxy, variables)?),
+            None => tls::proxy_from_env(&self.url, variables),
+        };
+        self.tls = tls.render_env(&hbs, variables)?;
+        Ok(self)
+    }
+
+    /// Http agent with proxy and tls options of spec
+    pub fn agent(&self, workdir: &Path) -> anyhow::Result<Agent> {
+        tls::build_agent(self.proxy.as_deref(), &self.tls, workdir)
+    }
+
+    /// Send request of spec and check response by expectations, returns body
+    /// of response
+    pub fn execute(&self, agent: &Agent, workdir: &Path) -> anyhow::Result<String> {
+        let method = self.method.to_uppercase();
+        println!("  {} {}", method, self.url);
+        let body = match self.payload {
+            Some(ref payload) => Some(payload.encode(workdir)?),
+            None => None,
+        };
+        let start_time = Instant::now();
+        let (status, resp_body) = self.send(agent, &method, &self.url, body)?;
+        let duration = format_duration(start_time.elapsed());
+        println!("  {} ({}): {}", status, duration, resp_body);
+        self.expect.check(status, &resp_body)?;
+        Ok(resp_body)
+    }
+
+    /// Send request with headers and auth of spec, returns status and body
+    /// of response, including the one of error status
+    fn send(
+        &self,
+        agent: &Agent,
+        method: &str,
+        url: &str,
+        body: Option<(Vec<u8>, String)>,
+    ) -> anyhow::Result<(u16, String)> {
+        let mut req = agent.request(method, url);
+        let timeout = self.timeout.unwrap_or(time::Duration::from_secs(10));
+        req = req.timeout(timeout);
+        req = req.set("User-Agent", USER_AGENT);
+        for (k, v) in &self.headers {
+            req = req.set(k, v);
+        }
+        if let Some(ref auth) = self.auth {
+            let data = body.as_ref().map(|(data, _)| data.as_slice());
+            req = auth.apply(req, data.unwrap_or_default())?;
+        }
+        let result = match body {
+            None => req.call(),
+            Some((data, content_type)) => req.set("Content-Type", &content_type).send_bytes(&data),
+        };
+        // error status is checked by expect
+        let resp: Response = match result {
+            Ok(resp) => resp,
+            Err(ureq::Error::Status(_, resp)) => resp,
+            Err(err) => return Err(err.into()),
+        };
+        let status = resp.status();
+        Ok((status, resp.into_string()?))
+    }
+
     fn render_env(&self, envs: Envs) -> anyhow::Result<Self> {
         let mut out = self.clone();
-        let hbs = Handlebars::new();
+        let hbs = handlebars();
         let variables = envs.build_env()?;
         out.method = hbs.render_template(&self.method, &variables)?;
         out.url = hbs.render_template(&self.url, &variables)?;
@@ -108,21 +273,26 @@ impl HookSpec {
             let v = hbs.render_template(&v, &variables)?;
             out.headers.insert(k, v);
         }
-        match &self.body {
-            None => {}
-            Some(body) => match body {
-                BodyData::FormData(form_data) => {
-                    let body = serde_urlencoded::to_string(form_data)?;
-                    let body = hbs.render_template(&body, &variables)?;
-                    out.body_string = body;
-                }
-                BodyData::JsonData(json_data) => {
-                    let body = serde_json::to_string(json_data)?;
-                    let body = hbs.render_template(&body, &variables)?;
-                    out.body_string = body;
-                }
-            },
+        if let Some(ref auth) = self.auth {
+            out.auth = Some(auth.render_env(&hbs, &variables)?);
         }
+        out = out.with_transport(self.proxy.as_ref(), &self.tls, &variables)?;
+        let payload = match (&self.body, &self.body_file) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow!("Only one of body and body_file could be specified"))
+            }
+            (Some(body), None) => body.render(&hbs, &variables)?,
+            (None, Some(path)) => Payload::file(hbs.render_template(path, &variables)?),
+            (None, None) => return Ok(out),
+        };
+        // content type in headers takes precedence over the one of body
+        let content_type = out
+            .headers
+            .keys()
+            .find(|k| k.eq_ignore_ascii_case("Content-Type"))
+            .cloned()
+            .and_then(|k| out.headers.remove(&k));
+        out.payload = Some(payload.with_content_type(content_type));
         Ok(out)
     }
 }
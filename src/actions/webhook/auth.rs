@@ -0,0 +1,171 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use handlebars::Handlebars;
+use hmac::{Hmac, Mac};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+use ureq::Request;
+
+/// Authentication of webhook request, values are rendered as handlebars
+/// templates
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum Auth {
+    /// `Authorization: Basic` header
+    Basic { username: String, password: String },
+    /// `Authorization: Bearer` header
+    Bearer(String),
+    /// HMAC-SHA256 signature of body in hex, GitHub style by default
+    Hmac {
+        secret: String,
+        #[serde(default = "default_signature_header")]
+        header: String,
+        #[serde(default = "default_signature_prefix")]
+        prefix: String,
+    },
+}
+
+fn default_signature_header() -> String {
+    "X-Hub-Signature-256".to_string()
+}
+
+fn default_signature_prefix() -> String {
+    "sha256=".to_string()
+}
+
+impl Auth {
+    pub fn render_env(
+        &self,
+        hbs: &Handlebars,
+        variables: &HashMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        let auth = match self {
+            Auth::Basic { username, password } => Auth::Basic {
+                username: hbs.render_template(username, variables)?,
+                password: hbs.render_template(password, variables)?,
+            },
+            Auth::Bearer(token) => Auth::Bearer(hbs.render_template(token, variables)?),
+            Auth::Hmac {
+                secret,
+                header,
+                prefix,
+            } => Auth::Hmac {
+                secret: hbs.render_template(secret, variables)?,
+                header: header.clone(),
+                prefix: prefix.clone(),
+            },
+        };
+        Ok(auth)
+    }
+
+    /// Header to set on request, signed over body
+    pub fn header(&self, body: &[u8]) -> anyhow::Result<(String, String)> {
+        let header = match self {
+            Auth::Basic { username, password } => {
+                let credentials = STANDARD.encode(format!("{}:{}", username, password));
+                (
+                    "Authorization".to_string(),
+                    format!("Basic {}", credentials),
+                )
+            }
+            Auth::Bearer(token) => ("Authorization".to_string(), format!("Bearer {}", token)),
+            Auth::Hmac {
+                secret,
+                header,
+                prefix,
+            } => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+                mac.update(body);
+                let signature = hex::encode(mac.finalize().into_bytes());
+                (header.clone(), format!("{}{}", prefix, signature))
+            }
+        };
+        Ok(header)
+    }
+
+    pub fn apply(&self, req: Request, body: &[u8]) -> anyhow::Result<Request> {
+        let (name, value) = self.header(body)?;
+        Ok(req.set(&name, &value))
+    }
+
+    /// Header with credentials masked, for printing
+    pub fn masked_header(&self) -> String {
+        match self {
+            Auth::Basic { .. } => "Authorization: Basic ***".to_string(),
+            Auth::Bearer(_) => "Authorization: Bearer ***".to_string(),
+            Auth::Hmac { header, prefix, .. } => format!("{}: {}***", header, prefix),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::webhook::handlebars;
+
+    fn auth(yaml: &str) -> Auth {
+        let value: serde_json::Value = serde_yaml::from_str(yaml).unwrap();
+        let vars = HashMap::from([(
+            "SECRET".to_string(),
+            "It's a Secret to Everybody".to_string(),
+        )]);
+        serde_json::from_value::<Auth>(value)
+            .unwrap()
+            .render_env(&handlebars(), &vars)
+            .unwrap()
+    }
+
+    #[test]
+    fn hmac_of_github_example() {
+        let auth = auth("hmac: {secret: '{{SECRET}}'}");
+        assert_eq!(
+            auth.header(b"Hello, World!").unwrap(),
+            (
+                "X-Hub-Signature-256".to_string(),
+                "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"
+                    .to_string()
+            )
+        );
+        assert_eq!(auth.masked_header(), "X-Hub-Signature-256: sha256=***");
+    }
+
+    #[test]
+    fn hmac_with_custom_header() {
+        let auth = auth("hmac: {secret: '{{SECRET}}', header: X-Signature, prefix: ''}");
+        assert_eq!(
+            auth.header(b"Hello, World!").unwrap(),
+            (
+                "X-Signature".to_string(),
+                "757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17".to_string()
+            )
+        );
+        assert_eq!(auth.masked_header(), "X-Signature: ***");
+    }
+
+    #[test]
+    fn basic_and_bearer() {
+        let auth = auth("basic: {username: Aladdin, password: open sesame}");
+        assert_eq!(
+            auth.header(b"").unwrap(),
+            (
+                "Authorization".to_string(),
+                "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==".to_string()
+            )
+        );
+        assert_eq!(auth.masked_header(), "Authorization: Basic ***");
+
+        let auth = Auth::Bearer("{{SECRET}}".to_string())
+            .render_env(
+                &handlebars(),
+                &HashMap::from([("SECRET".to_string(), "t0k".to_string())]),
+            )
+            .unwrap();
+        assert_eq!(
+            auth.header(b"").unwrap(),
+            ("Authorization".to_string(), "Bearer t0k".to_string())
+        );
+        assert_eq!(auth.masked_header(), "Authorization: Bearer ***");
+    }
+}
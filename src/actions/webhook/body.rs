@@ -0,0 +1,386 @@
+use crate::helper::is_inside;
+use anyhow::{anyhow, Context as _};
+use handlebars::Handlebars;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type FormData = HashMap<String, String>;
+
+const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+const OCTET_CONTENT_TYPE: &str = "application/octet-stream";
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+pub enum BodyData {
+    #[serde(rename = "formData")]
+    FormData(FormData),
+    /// JSON of any shape, strings are rendered as templates, while other
+    /// values are kept as typed
+    #[serde(rename = "jsonData")]
+    JsonData(Value),
+    /// Data with explicit content type
+    #[serde(rename = "raw")]
+    Raw(RawBody),
+    /// Plain text
+    #[serde(rename = "text")]
+    Text(String),
+    /// Multipart form, with files from workspace
+    #[serde(rename = "multipart")]
+    Multipart(Multipart),
+}
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RawBody {
+    content_type: String,
+    data: String,
+}
+
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Multipart {
+    /// Text fields
+    #[serde(default)]
+    fields: HashMap<String, String>,
+    /// File fields, with paths relative to workspace
+    #[serde(default)]
+    files: HashMap<String, String>,
+}
+
+/// Body rendered with env, files are read only when it is encoded
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Text {
+        content_type: String,
+        data: String,
+    },
+    Multipart {
+        fields: BTreeMap<String, String>,
+        files: BTreeMap<String, String>,
+    },
+    File {
+        content_type: String,
+        path: String,
+    },
+}
+
+impl BodyData {
+    pub fn render(
+        &self,
+        hbs: &Handlebars,
+        variables: &HashMap<String, String>,
+    ) -> anyhow::Result<Payload> {
+        let payload = match self {
+            BodyData::FormData(form_data) => {
+                // render before encoding, so values are encoded as a whole
+                let mut rendered = BTreeMap::new();
+                for (k, v) in form_data {
+                    rendered.insert(k.clone(), hbs.render_template(v, variables)?);
+                }
+                Payload::Text {
+                    content_type: "application/x-www-form-urlencoded".to_string(),
+                    data: serde_urlencoded::to_string(rendered)?,
+                }
+            }
+            BodyData::JsonData(json_data) => {
+                let rendered = render_json(hbs, json_data, variables)?;
+                Payload::Text {
+                    content_type: "application/json".to_string(),
+                    data: serde_json::to_string(&rendered)?,
+                }
+            }
+            BodyData::Raw(raw) => Payload::Text {
+                content_type: raw.content_type.clone(),
+                data: hbs.render_template(&raw.data, variables)?,
+            },
+            BodyData::Text(text) => Payload::Text {
+                content_type: TEXT_CONTENT_TYPE.to_string(),
+                data: hbs.render_template(text, variables)?,
+            },
+            BodyData::Multipart(multipart) => {
+                let mut fields = BTreeMap::new();
+                for (k, v) in &multipart.fields {
+                    fields.insert(k.clone(), hbs.render_template(v, variables)?);
+                }
+                let mut files = BTreeMap::new();
+                for (k, v) in &multipart.files {
+                    files.insert(k.clone(), hbs.render_template(v, variables)?);
+                }
+                Payload::Multipart { fields, files }
+            }
+        };
+        Ok(payload)
+    }
+}
+
+impl Payload {
+    /// Body of file in workspace
+    pub fn file(path: String) -> Self {
+        Payload::File {
+            content_type: OCTET_CONTENT_TYPE.to_string(),
+            path,
+        }
+    }
+
+    /// Override content type, e.g. by header, except for multipart whose
+    /// boundary is generated
+    pub fn with_content_type(mut self, content_type: Option<String>) -> Self {
+        if let Some(value) = content_type {
+            match self {
+                Payload::Text {
+                    ref mut content_type,
+                    ..
+                }
+                | Payload::File {
+                    ref mut content_type,
+                    ..
+                } => *content_type = value,
+                Payload::Multipart { .. } => {}
+            }
+        }
+        self
+    }
+
+    /// Encode to bytes and content type, files are read from workdir
+    pub fn encode(&self, workdir: &Path) -> anyhow::Result<(Vec<u8>, String)> {
+        match self {
+            Payload::Text { content_type, data } => {
+                Ok((data.clone().into_bytes(), content_type.clone()))
+            }
+            Payload::Multipart { fields, files } => {
+                let boundary = boundary();
+                let mut body = Vec::new();
+                for (name, value) in fields {
+                    body.extend(format!("--{}\r\n", boundary).as_bytes());
+                    body.extend(
+                        format!("Content-Disposition: form-data; name=\"{}\"\r\n\r\n", name)
+                            .as_bytes(),
+                    );
+                    body.extend(value.as_bytes());
+                    body.extend(b"\r\n");
+                }
+                for (name, path) in files {
+                    let data = read_file(workdir, path)?;
+                    let filename = Path::new(path)
+                        .file_name()
+                        .map(|name| name.to_string_lossy().to_string())
+                        .unwrap_or_default();
+                    body.extend(format!("--{}\r\n", boundary).as_bytes());
+                    body.extend(
+                        format!(
+                            "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n",
+                            name, filename
+                        )
+                        .as_bytes(),
+                    );
+                    body.extend(format!("Content-Type: {}\r\n\r\n", OCTET_CONTENT_TYPE).as_bytes());
+                    body.extend(data);
+                    body.extend(b"\r\n");
+                }
+                body.extend(format!("--{}--\r\n", boundary).as_bytes());
+                let content_type = format!("multipart/form-data; boundary={}", boundary);
+                Ok((body, content_type))
+            }
+            Payload::File { content_type, path } => {
+                Ok((read_file(workdir, path)?, content_type.clone()))
+            }
+        }
+    }
+
+    /// Print body, without reading files
+    pub fn plan(&self) {
+        match self {
+            Payload::Text { content_type, data } => {
+                println!("  Content-Type: {}", content_type);
+                println!();
+                println!("  {}", data);
+            }
+            Payload::Multipart { fields, files } => {
+                println!("  Content-Type: multipart/form-data");
+                println!();
+                for (name, value) in fields {
+                    println!("  {}={}", name, value);
+                }
+                for (name, path) in files {
+                    println!("  {}=@{}", name, path);
+                }
+            }
+            Payload::File { content_type, path } => {
+                println!("  Content-Type: {}", content_type);
+                println!();
+                println!("  @{}", path);
+            }
+        }
+    }
+}
+
+/// Render strings in JSON value recursively, including keys of objects
+fn render_json(
+    hbs: &Handlebars,
+    value: &Value,
+    variables: &HashMap<String, String>,
+) -> anyhow::Result<Value> {
+    let rendered = match value {
+        Value::String(s) => Value::String(hbs.render_template(s, variables)?),
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| render_json(hbs, item, variables))
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        Value::Object(map) => {
+            let mut rendered = Map::new();
+            for (k, v) in map {
+                rendered.insert(
+                    hbs.render_template(k, variables)?,
+                    render_json(hbs, v, variables)?,
+                );
+            }
+            Value::Object(rendered)
+        }
+        other => other.clone(),
+    };
+    Ok(rendered)
+}
+
+/// Read file of path relative to workdir
+fn read_file(workdir: &Path, path: &str) -> anyhow::Result<Vec<u8>> {
+    if !is_inside(Path::new(path)) {
+        return Err(anyhow!(
+            "body file '{}' should be relative and inside workspace",
+            path
+        ));
+    }
+    fs::read(workdir.join(path)).with_context(|| format!("Failed to read body file {}", path))
+}
+
+fn boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("arrow-boundary-{:x}", nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::webhook::handlebars;
+    use serde_json::json;
+
+    fn render(yaml: &str, vars: &[(&str, &str)]) -> (String, String) {
+        // through a buffered value like `runner` tagged actions, as
+        // serde_yaml expects a tag for enums at top level
+        let value: Value = serde_yaml::from_str(yaml).unwrap();
+        let body: BodyData = serde_json::from_value(value).unwrap();
+        let vars = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        match body.render(&handlebars(), &vars).unwrap() {
+            Payload::Text { content_type, data } => (content_type, data),
+            payload => panic!("not text: {:?}", payload),
+        }
+    }
+
+    #[test]
+    fn json_data_escapes_values() {
+        let message = "Fix \"quotes\" & <tags>\n\nin body\\";
+        let (content_type, data) = render(
+            "jsonData: {message: '{{MESSAGE}}', '{{KEY}}': x}",
+            &[("MESSAGE", message), ("KEY", "a\"b")],
+        );
+        assert_eq!(content_type, "application/json");
+        let value: Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(value, json!({"message": message, "a\"b": "x"}));
+    }
+
+    #[test]
+    fn json_data_keeps_shape_and_types() {
+        let (_, data) = render(
+            "jsonData:\n  \
+               count: 3\n  \
+               ratio: 0.5\n  \
+               ok: true\n  \
+               none: null\n  \
+               quoted: '3'\n  \
+               nested: {branch: '{{BRANCH}}', tags: [a, 1, false, {rev: '{{REV}}'}]}\n",
+            &[("BRANCH", "main"), ("REV", "abc")],
+        );
+        let value: Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "count": 3,
+                "ratio": 0.5,
+                "ok": true,
+                "none": null,
+                "quoted": "3",
+                "nested": {"branch": "main", "tags": ["a", 1, false, {"rev": "abc"}]},
+            })
+        );
+    }
+
+    #[test]
+    fn form_data_encodes_rendered_values() {
+        let (content_type, data) = render(
+            "formData: {message: '{{MESSAGE}}', branch: '{{BRANCH}}'}",
+            &[("MESSAGE", "a&b=c d"), ("BRANCH", "main")],
+        );
+        assert_eq!(content_type, "application/x-www-form-urlencoded");
+        assert_eq!(data, "branch=main&message=a%26b%3Dc+d");
+        let decoded: BTreeMap<String, String> = serde_urlencoded::from_str(&data).unwrap();
+        assert_eq!(decoded["message"], "a&b=c d");
+    }
+
+    #[test]
+    fn text_and_raw_are_not_escaped() {
+        let (content_type, data) = render("text: '{{MESSAGE}}'", &[("MESSAGE", "<a & b>")]);
+        assert_eq!(
+            (content_type.as_str(), data.as_str()),
+            (TEXT_CONTENT_TYPE, "<a & b>")
+        );
+
+        let (content_type, data) = render(
+            "raw: {content_type: text/xml, data: '<m>{{MESSAGE}}</m>'}",
+            &[("MESSAGE", "a")],
+        );
+        assert_eq!(
+            (content_type.as_str(), data.as_str()),
+            ("text/xml", "<m>a</m>")
+        );
+    }
+
+    #[test]
+    fn multipart_reads_files_of_workdir() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("report.txt"), "passed").unwrap();
+        let payload = Payload::Multipart {
+            fields: BTreeMap::from([("branch".to_string(), "main".to_string())]),
+            files: BTreeMap::from([("report".to_string(), "report.txt".to_string())]),
+        };
+        let (body, content_type) = payload.encode(dir.path()).unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/form-data; boundary=")
+            .unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert_eq!(
+            body,
+            format!(
+                "--{b}\r\nContent-Disposition: form-data; name=\"branch\"\r\n\r\nmain\r\n\
+                 --{b}\r\nContent-Disposition: form-data; name=\"report\"; filename=\"report.txt\"\r\n\
+                 Content-Type: application/octet-stream\r\n\r\npassed\r\n--{b}--\r\n",
+                b = boundary
+            )
+        );
+
+        let err = Payload::file("../secret".to_string())
+            .encode(dir.path())
+            .unwrap_err();
+        assert!(err.to_string().contains("inside workspace"), "{}", err);
+    }
+}
@@ -0,0 +1,273 @@
+use crate::decode;
+use crate::helper::is_env_name;
+use anyhow::anyhow;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{de, Deserialize, Deserializer};
+use serde_json::Value;
+use serde_json_path::JsonPath;
+use std::collections::{BTreeMap, HashMap};
+
+/// Expectations on response, by default status should be less than 400
+#[derive(Debug, Default, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Expect {
+    /// Allowed status codes
+    #[serde(default, deserialize_with = "decode::one_or_seq")]
+    #[schemars(schema_with = "decode::one_or_seq_schema::<u16>")]
+    status: Vec<u16>,
+    /// Regex that body should match
+    #[serde(default, deserialize_with = "regex")]
+    #[schemars(with = "Option<String>")]
+    body: Option<Regex>,
+    /// JSON paths of body, and values they should equal
+    #[serde(default, deserialize_with = "json_path_map")]
+    #[schemars(with = "HashMap<String, Value>")]
+    json: Vec<(Query, Value)>,
+}
+
+/// JSON path compiled upon deserialization
+#[derive(Debug, Clone)]
+pub struct Query {
+    path: JsonPath,
+    raw: String,
+}
+
+impl Query {
+    fn parse(raw: &str) -> anyhow::Result<Self> {
+        let path =
+            JsonPath::parse(raw).map_err(|err| anyhow!("Invalid JSON path '{}': {}", raw, err))?;
+        Ok(Query {
+            path,
+            raw: raw.to_string(),
+        })
+    }
+
+    /// First value matched in body
+    fn first<'a>(&self, body: &'a Value) -> Option<&'a Value> {
+        self.path.query(body).first()
+    }
+}
+
+impl Expect {
+    /// Check status and body of response
+    pub fn check(&self, status: u16, body: &str) -> anyhow::Result<()> {
+        if self.status.is_empty() {
+            if status >= 400 {
+                return Err(anyhow!("Unexpected status {}", status));
+            }
+        } else if !self.status.contains(&status) {
+            return Err(anyhow!(
+                "Unexpected status {}, expect one of {:?}",
+                status,
+                self.status
+            ));
+        }
+        if let Some(ref regex) = self.body {
+            if !regex.is_match(body) {
+                return Err(anyhow!("Body does not match /{}/", regex));
+            }
+        }
+        if !self.json.is_empty() {
+            let json = parse_json(body)?;
+            for (query, expected) in &self.json {
+                match query.first(&json) {
+                    Some(value) if value == expected => {}
+                    Some(value) => {
+                        return Err(anyhow!(
+                            "Expect {} to be {}, but got {}",
+                            query.raw,
+                            expected,
+                            value
+                        ))
+                    }
+                    None => {
+                        return Err(anyhow!(
+                            "Expect {} to be {}, but not found",
+                            query.raw,
+                            expected
+                        ))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Extract outputs from JSON body, strings are taken as is, while others
+/// are in JSON
+pub fn extract_outputs(
+    outputs: &[(Query, String)],
+    body: &str,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let json = parse_json(body)?;
+    let mut extracted = Vec::new();
+    for (query, key) in outputs {
+        let value = query
+            .first(&json)
+            .ok_or_else(|| anyhow!("Output {} not found by {}", key, query.raw))?;
+        let value = match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        extracted.push((key.clone(), value));
+    }
+    Ok(extracted)
+}
+
+fn parse_json(body: &str) -> anyhow::Result<Value> {
+    serde_json::from_str(body).map_err(|err| anyhow!("Body is not JSON: {}", err))
+}
+
+fn regex<'d, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'d>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(pattern) => Regex::new(&pattern)
+            .map(Some)
+            .map_err(|err| de::Error::custom(format!("Invalid regex: {}", err))),
+        None => Ok(None),
+    }
+}
+
+/// Deserialize map with JSON paths as keys, in order of paths
+fn json_path_map<'d, D, V>(deserializer: D) -> Result<Vec<(Query, V)>, D::Error>
+where
+    D: Deserializer<'d>,
+    V: Deserialize<'d>,
+{
+    BTreeMap::<String, V>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(path, value)| {
+            Query::parse(&path)
+                .map(|query| (query, value))
+                .map_err(|err| de::Error::custom(format!("{:#}", err)))
+        })
+        .collect()
+}
+
+/// Deserialize outputs map of env key to JSON path, as pairs of path and
+/// key
+pub fn outputs<'d, D>(deserializer: D) -> Result<Vec<(Query, String)>, D::Error>
+where
+    D: Deserializer<'d>,
+{
+    BTreeMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, path)| {
+            if !is_env_name(&key) {
+                return Err(de::Error::custom(format!("Invalid env name '{}'", key)));
+            }
+            Query::parse(&path)
+                .map(|query| (query, key))
+                .map_err(|err| de::Error::custom(format!("{:#}", err)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn expect(yaml: &str) -> Expect {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Outputs {
+        #[serde(deserialize_with = "outputs")]
+        outputs: Vec<(Query, String)>,
+    }
+
+    fn outputs_of(yaml: &str) -> Result<Vec<(Query, String)>, serde_yaml::Error> {
+        serde_yaml::from_str::<Outputs>(yaml).map(|o| o.outputs)
+    }
+
+    #[test]
+    fn status_below_400_by_default() {
+        let expect = Expect::default();
+        assert!(expect.check(200, "").is_ok());
+        assert!(expect.check(302, "").is_ok());
+        let err = expect.check(404, "").unwrap_err();
+        assert_eq!(err.to_string(), "Unexpected status 404");
+    }
+
+    #[test]
+    fn status_in_list() {
+        assert!(expect("status: 202").check(202, "").is_ok());
+        assert!(expect("status: 202").check(200, "").is_err());
+        let expect = expect("status: [200, 404]");
+        assert!(expect.check(404, "").is_ok());
+        let err = expect.check(201, "").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unexpected status 201, expect one of [200, 404]"
+        );
+    }
+
+    #[test]
+    fn body_regex() {
+        let expect = expect("body: '^ok\\b'");
+        assert!(expect.check(200, "ok, done").is_ok());
+        let err = expect.check(200, "not ok").unwrap_err();
+        assert!(err.to_string().contains("does not match"));
+        let err = serde_yaml::from_str::<Expect>("body: '('").unwrap_err();
+        assert!(err.to_string().contains("Invalid regex"));
+    }
+
+    #[test]
+    fn json_paths() {
+        let expect = expect("json: {'$.status': done, '$.jobs[0].id': 7}");
+        let body = json!({"status": "done", "jobs": [{"id": 7}]}).to_string();
+        assert!(expect.check(200, &body).is_ok());
+
+        let body = json!({"status": "running", "jobs": [{"id": 7}]}).to_string();
+        let err = expect.check(200, &body).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expect $.status to be \"done\", but got \"running\""
+        );
+
+        let body = json!({"status": "done"}).to_string();
+        let err = expect.check(200, &body).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expect $.jobs[0].id to be 7, but not found"
+        );
+
+        let err = expect.check(200, "<html>").unwrap_err();
+        assert!(err.to_string().starts_with("Body is not JSON"));
+
+        let err = serde_yaml::from_str::<Expect>("json: {'$.[': 1}").unwrap_err();
+        assert!(err.to_string().contains("Invalid JSON path '$.['"));
+    }
+
+    #[test]
+    fn extract_outputs_of_body() {
+        let outputs =
+            outputs_of("outputs: {JOB_ID: '$.id', TAGS: '$.tags', URL: '$.links.self'}").unwrap();
+        let body = json!({"id": 42, "tags": ["a", "b"], "links": {"self": "http://x/42"}});
+        let mut extracted = extract_outputs(&outputs, &body.to_string()).unwrap();
+        extracted.sort();
+        assert_eq!(
+            extracted,
+            vec![
+                ("JOB_ID".to_string(), "42".to_string()),
+                ("TAGS".to_string(), "[\"a\",\"b\"]".to_string()),
+                ("URL".to_string(), "http://x/42".to_string()),
+            ]
+        );
+
+        let err = extract_outputs(&outputs, "{}").unwrap_err();
+        assert!(err.to_string().contains("not found by"));
+    }
+
+    #[test]
+    fn outputs_need_env_names() {
+        let err = outputs_of("outputs: {'job-id': '$.id'}").unwrap_err();
+        assert!(err.to_string().contains("Invalid env name 'job-id'"));
+    }
+}
@@ -0,0 +1,435 @@
+use anyhow::{anyhow, Context as _};
+use handlebars::Handlebars;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName};
+use rustls_pemfile::Item;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use ureq::{Agent, AgentBuilder, Proxy};
+
+/// Env of CA bundle, used if `ca_file` is not specified
+const CA_FILE_ENV: &str = "SSL_CERT_FILE";
+
+/// TLS settings, paths are relative to workspace
+#[derive(Debug, Deserialize, Clone, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TlsOptions {
+    /// CA bundle in PEM to verify server with, in addition to native roots
+    ca_file: Option<String>,
+    /// Skip verifying server certificate, for internal hosts only
+    #[serde(default)]
+    insecure: bool,
+    /// Client certificate chain in PEM, for mutual TLS
+    client_cert: Option<String>,
+    /// Private key in PEM of client certificate
+    client_key: Option<String>,
+}
+
+impl TlsOptions {
+    pub fn render_env(
+        &self,
+        hbs: &Handlebars,
+        variables: &HashMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        let render = |value: &Option<String>| -> anyhow::Result<Option<String>> {
+            match value {
+                Some(value) => Ok(Some(hbs.render_template(value, variables)?)),
+                None => Ok(None),
+            }
+        };
+        Ok(TlsOptions {
+            ca_file: render(&self.ca_file)?.or_else(|| variables.get(CA_FILE_ENV).cloned()),
+            insecure: self.insecure,
+            client_cert: render(&self.client_cert)?,
+            client_key: render(&self.client_key)?,
+        })
+    }
+
+    fn is_default(&self) -> bool {
+        self.ca_file.is_none()
+            && !self.insecure
+            && self.client_cert.is_none()
+            && self.client_key.is_none()
+    }
+
+    pub fn plan(&self) {
+        if let Some(ref ca_file) = self.ca_file {
+            println!("  tls ca_file: {}", ca_file);
+        }
+        if self.insecure {
+            println!("  tls insecure: server certificate is not verified");
+        }
+        if let Some(ref client_cert) = self.client_cert {
+            println!("  tls client_cert: {}", client_cert);
+        }
+    }
+
+    /// Build client config, or none to use the default one
+    fn client_config(&self, workdir: &Path) -> anyhow::Result<Option<ClientConfig>> {
+        if self.is_default() {
+            return Ok(None);
+        }
+        let builder = ClientConfig::builder().with_safe_defaults();
+        let verifier: Arc<dyn ServerCertVerifier> = if self.insecure {
+            Arc::new(NoVerifier)
+        } else {
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_native_certs::load_native_certs()? {
+                // skip those not supported, as curl does
+                let _ = roots.add(&Certificate(cert.0));
+            }
+            if let Some(ref ca_file) = self.ca_file {
+                for cert in read_certs(&workdir.join(ca_file))? {
+                    roots
+                        .add(&cert)
+                        .with_context(|| format!("Invalid certificate in {}", ca_file))?;
+                }
+            }
+            Arc::new(WebPkiVerifier::new(roots, None))
+        };
+        let builder = builder.with_custom_certificate_verifier(verifier);
+        let config = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let certs = read_certs(&workdir.join(cert))?;
+                let key = read_key(&workdir.join(key))?;
+                builder
+                    .with_client_auth_cert(certs, key)
+                    .context("Invalid client certificate or key")?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => {
+                return Err(anyhow!(
+                    "Both client_cert and client_key should be specified"
+                ))
+            }
+        };
+        Ok(Some(config))
+    }
+}
+
+/// Build http agent with proxy and TLS settings
+pub fn build_agent(proxy: Option<&str>, tls: &TlsOptions, workdir: &Path) -> anyhow::Result<Agent> {
+    let mut builder = AgentBuilder::new();
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(Proxy::new(proxy).context("Invalid proxy")?);
+    }
+    if let Some(config) = tls.client_config(workdir)? {
+        builder = builder.tls_config(Arc::new(config));
+    }
+    Ok(builder.build())
+}
+
+/// Proxy from env of action for url, per `HTTPS_PROXY`, `HTTP_PROXY`,
+/// `ALL_PROXY`, and hosts or domains in `NO_PROXY` excluded
+pub fn proxy_from_env(url: &str, variables: &HashMap<String, String>) -> Option<String> {
+    let get = |key: &str| {
+        variables
+            .get(key)
+            .or_else(|| variables.get(&key.to_lowercase()))
+            .filter(|value| !value.is_empty())
+    };
+    let (scheme, rest) = url.split_once("://")?;
+    let host = rest
+        .split(['/', '?', '#'])
+        .next()?
+        .rsplit('@')
+        .next()?
+        .split(':')
+        .next()?;
+    if let Some(no_proxy) = get("NO_PROXY") {
+        let excluded = no_proxy.split(',').map(str::trim).any(|pattern| {
+            let domain = pattern.trim_start_matches('.');
+            pattern == "*" || host == domain || host.ends_with(&format!(".{}", domain))
+        });
+        if excluded {
+            return None;
+        }
+    }
+    let proxy = match scheme {
+        "https" => get("HTTPS_PROXY"),
+        _ => get("HTTP_PROXY"),
+    };
+    proxy.or_else(|| get("ALL_PROXY")).cloned()
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to read certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", path.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to read private key from {}", path.display()))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No private key found in {}", path.display()))
+}
+
+/// Accept any server certificate
+struct NoVerifier;
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa};
+    use rustls::server::AllowAnyAuthenticatedClient;
+    use rustls::{ServerConfig, ServerConnection, StreamOwned};
+    use std::io::{self, BufRead, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+    use tempfile::TempDir;
+
+    /// CA, server and client certificates signed by the CA, written to a
+    /// workdir as `ca.pem`, `client.pem` and `client.key`
+    struct Pki {
+        workdir: TempDir,
+        ca: Certificate,
+        server: (Vec<Certificate>, PrivateKey),
+    }
+
+    impl Pki {
+        fn new() -> Pki {
+            let mut params = CertificateParams::new(Vec::new());
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = rcgen::Certificate::from_params(params).unwrap();
+            let server = rcgen::Certificate::from_params(CertificateParams::new(vec![
+                "localhost".to_string()
+            ]))
+            .unwrap();
+            let client =
+                rcgen::Certificate::from_params(CertificateParams::new(vec!["client".to_string()]))
+                    .unwrap();
+
+            let workdir = tempfile::tempdir().unwrap();
+            let write = |name: &str, content: String| {
+                std::fs::write(workdir.path().join(name), content).unwrap()
+            };
+            write("ca.pem", ca.serialize_pem().unwrap());
+            write("client.pem", client.serialize_pem_with_signer(&ca).unwrap());
+            write("client.key", client.serialize_private_key_pem());
+            Pki {
+                ca: Certificate(ca.serialize_der().unwrap()),
+                server: (
+                    vec![Certificate(server.serialize_der_with_signer(&ca).unwrap())],
+                    PrivateKey(server.serialize_private_key_der()),
+                ),
+                workdir,
+            }
+        }
+
+        /// Serve `ok` over TLS on a local port, requiring a client
+        /// certificate signed by the CA if `mutual`
+        fn serve(&self, mutual: bool) -> u16 {
+            let builder = ServerConfig::builder().with_safe_defaults();
+            let builder = if mutual {
+                let mut roots = RootCertStore::empty();
+                roots.add(&self.ca).unwrap();
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            } else {
+                builder.with_no_client_auth()
+            };
+            let (certs, key) = self.server.clone();
+            let config = Arc::new(builder.with_single_cert(certs, key).unwrap());
+
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            thread::spawn(move || {
+                for tcp in listener.incoming() {
+                    let conn = ServerConnection::new(config.clone()).unwrap();
+                    let mut tls = StreamOwned::new(conn, tcp.unwrap());
+                    // handshake errors are seen by the client
+                    let _ = read_head(&mut tls).and_then(|_| {
+                        tls.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")?;
+                        tls.flush()
+                    });
+                }
+            });
+            port
+        }
+
+        fn get(&self, tls: &TlsOptions, proxy: Option<&str>, port: u16) -> anyhow::Result<String> {
+            let agent = build_agent(proxy, tls, self.workdir.path())?;
+            let response = agent.get(&format!("https://localhost:{}/", port)).call()?;
+            Ok(response.into_string()?)
+        }
+    }
+
+    /// Read request line and headers
+    fn read_head(stream: &mut impl Read) -> io::Result<Vec<String>> {
+        let mut reader = io::BufReader::new(stream);
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if line.trim_end().is_empty() {
+                return Ok(lines);
+            }
+            lines.push(line.trim_end().to_string());
+        }
+    }
+
+    /// Proxy tunneling by CONNECT on a local port, sends the request line
+    /// of each tunnel
+    fn proxy() -> (u16, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let mut client = client.unwrap();
+                // nothing is buffered past the head, as the client waits
+                // for the reply before starting TLS
+                let head = read_head(&mut &client).unwrap();
+                let target = head[0].split(' ').nth(1).unwrap().to_string();
+                sender.send(head[0].clone()).unwrap();
+                let mut server =
+                    TcpStream::connect(target.replace("localhost", "127.0.0.1")).unwrap();
+                client
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .unwrap();
+                let (mut client_read, mut server_write) =
+                    (client.try_clone().unwrap(), server.try_clone().unwrap());
+                thread::spawn(move || io::copy(&mut client_read, &mut server_write));
+                thread::spawn(move || io::copy(&mut server, &mut client));
+            }
+        });
+        (port, receiver)
+    }
+
+    fn options(yaml: &str) -> TlsOptions {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn verify_server_by_ca_file() {
+        let pki = Pki::new();
+        let port = pki.serve(false);
+        let err = pki.get(&TlsOptions::default(), None, port).unwrap_err();
+        assert!(format!("{:#}", err).contains("UnknownIssuer"), "{:#}", err);
+
+        let body = pki.get(&options("ca_file: ca.pem"), None, port).unwrap();
+        assert_eq!(body, "ok");
+        let body = pki.get(&options("insecure: true"), None, port).unwrap();
+        assert_eq!(body, "ok");
+    }
+
+    #[test]
+    fn ca_file_from_env() {
+        let hbs = Handlebars::new();
+        let variables = HashMap::from([(CA_FILE_ENV.to_string(), "/etc/ca.pem".to_string())]);
+        let tls = TlsOptions::default().render_env(&hbs, &variables).unwrap();
+        assert_eq!(tls.ca_file.as_deref(), Some("/etc/ca.pem"));
+        let tls = options("ca_file: '{{DIR}}/ca.pem'")
+            .render_env(
+                &hbs,
+                &HashMap::from([("DIR".to_string(), "certs".to_string())]),
+            )
+            .unwrap();
+        assert_eq!(tls.ca_file.as_deref(), Some("certs/ca.pem"));
+    }
+
+    #[test]
+    fn client_certificate() {
+        let pki = Pki::new();
+        let port = pki.serve(true);
+        assert!(pki.get(&options("ca_file: ca.pem"), None, port).is_err());
+
+        let tls = options("{ca_file: ca.pem, client_cert: client.pem, client_key: client.key}");
+        assert_eq!(pki.get(&tls, None, port).unwrap(), "ok");
+
+        let tls = options("{ca_file: ca.pem, client_cert: client.pem}");
+        let err = pki.get(&tls, None, port).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Both client_cert and client_key should be specified"
+        );
+        let tls = options("{ca_file: ca.pem, client_cert: client.pem, client_key: ca.pem}");
+        let err = pki.get(&tls, None, port).unwrap_err();
+        assert!(err.to_string().starts_with("No private key found in"));
+    }
+
+    #[test]
+    fn tunnel_through_proxy() {
+        let pki = Pki::new();
+        let port = pki.serve(true);
+        let (proxy_port, requests) = proxy();
+        let proxy = format!("http://127.0.0.1:{}", proxy_port);
+        let tls = options("{ca_file: ca.pem, client_cert: client.pem, client_key: client.key}");
+        assert_eq!(pki.get(&tls, Some(&proxy), port).unwrap(), "ok");
+        assert_eq!(
+            requests.try_recv().unwrap(),
+            format!("CONNECT localhost:{} HTTP/1.1", port)
+        );
+    }
+
+    #[test]
+    fn proxy_by_env() {
+        let variables = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        let env = variables(&[
+            ("HTTPS_PROXY", "http://secure:3128"),
+            ("http_proxy", "http://plain:3128"),
+            ("NO_PROXY", "localhost, .internal"),
+        ]);
+        let proxy = |url| proxy_from_env(url, &env);
+        assert_eq!(
+            proxy("https://example.com/x").as_deref(),
+            Some("http://secure:3128")
+        );
+        assert_eq!(
+            proxy("http://user@example.com:80").as_deref(),
+            Some("http://plain:3128")
+        );
+        assert_eq!(proxy("https://localhost:8443/"), None);
+        assert_eq!(proxy("https://ci.internal/hook"), None);
+        assert_eq!(proxy("https://internal/hook"), None);
+        assert_eq!(
+            proxy("https://notinternal/hook").as_deref(),
+            Some("http://secure:3128")
+        );
+
+        let env = variables(&[("ALL_PROXY", "socks5://all:1080"), ("HTTP_PROXY", "")]);
+        assert_eq!(
+            proxy_from_env("http://example.com", &env).as_deref(),
+            Some("socks5://all:1080")
+        );
+        assert_eq!(proxy_from_env("http://example.com", &HashMap::new()), None);
+    }
+}
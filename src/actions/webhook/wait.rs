@@ -0,0 +1,181 @@
+use super::expect::{self, Expect, Query};
+use crate::helper::format_duration;
+use anyhow::anyhow;
+use handlebars::Handlebars;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Poll url after the request, until the remote job is done, e.g. url of
+/// job from `outputs` of the request
+#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct WaitFor {
+    url: String,
+    #[serde(default = "default_method")]
+    method: String,
+    /// Seconds between polls
+    #[serde(default = "default_interval")]
+    interval: u64,
+    /// Seconds to wait at most
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    /// Conditions of response once job succeeded, required as the default
+    /// status check would pass on the first poll of a running job
+    success: Expect,
+    /// Conditions of response once job failed, to stop waiting
+    failure: Option<Expect>,
+    /// Env keys and JSON paths of the last response to extract them from
+    #[serde(default, deserialize_with = "expect::outputs")]
+    #[schemars(with = "HashMap<String, String>")]
+    pub outputs: Vec<(Query, String)>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+fn default_timeout() -> u64 {
+    600
+}
+
+impl WaitFor {
+    pub fn render_env(
+        &self,
+        hbs: &Handlebars,
+        variables: &HashMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        let mut out = self.clone();
+        out.url = hbs.render_template(&self.url, variables)?;
+        out.method = hbs.render_template(&self.method, variables)?.to_uppercase();
+        Ok(out)
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn plan(&self) {
+        println!(
+            "  then wait for {} {}, every {}s up to {}s",
+            self.method.to_uppercase(),
+            self.url,
+            self.interval,
+            self.timeout
+        );
+    }
+
+    /// Poll by `send` till success or failure condition is met, returns the
+    /// body of last response. Errors of sending are retried till timeout.
+    pub fn poll(
+        &self,
+        mut send: impl FnMut() -> anyhow::Result<(u16, String)>,
+    ) -> anyhow::Result<String> {
+        println!("  waiting for {} {}", self.method, self.url);
+        let interval = Duration::from_secs(self.interval);
+        let timeout = Duration::from_secs(self.timeout);
+        let start_time = Instant::now();
+        loop {
+            let elapsed = format_duration(start_time.elapsed());
+            match send() {
+                Ok((status, body)) => {
+                    if let Some(ref failure) = self.failure {
+                        if failure.check(status, &body).is_ok() {
+                            return Err(anyhow!("Job failed, {}: {}", status, body));
+                        }
+                    }
+                    match self.success.check(status, &body) {
+                        Ok(()) => {
+                            println!("  {} ({}): {}", status, elapsed, body);
+                            return Ok(body);
+                        }
+                        Err(err) => println!("  {} ({}): {:#}", status, elapsed, err),
+                    }
+                }
+                Err(err) => println!("  Error ({}): {:#}", elapsed, err),
+            }
+            if start_time.elapsed() + interval > timeout {
+                return Err(anyhow!(
+                    "Timed out waiting for {} in {}s",
+                    self.url,
+                    self.timeout
+                ));
+            }
+            thread::sleep(interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wait_for(yaml: &str) -> WaitFor {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    /// Replies the responses in turn, fails the test if polled once more
+    fn replies(
+        responses: Vec<anyhow::Result<(u16, String)>>,
+    ) -> impl FnMut() -> anyhow::Result<(u16, String)> {
+        let mut responses = responses.into_iter();
+        move || responses.next().expect("polled after the last response")
+    }
+
+    #[test]
+    fn success_is_required() {
+        let err = serde_yaml::from_str::<WaitFor>("url: http://x/job").unwrap_err();
+        assert!(err.to_string().contains("missing field `success`"));
+
+        let wait = wait_for("url: http://x/job\nsuccess: {status: 200}");
+        assert_eq!(wait.method(), "GET");
+        assert_eq!((wait.interval, wait.timeout), (5, 600));
+    }
+
+    #[test]
+    fn poll_till_success() {
+        let wait = wait_for(
+            "url: http://x/job\ninterval: 0\ntimeout: 5\nsuccess: {json: {'$.state': done}}",
+        );
+        let body = wait
+            .poll(replies(vec![
+                Err(anyhow!("connection refused")),
+                Ok((200, r#"{"state": "running"}"#.to_string())),
+                Ok((200, r#"{"state": "done"}"#.to_string())),
+            ]))
+            .unwrap();
+        assert_eq!(body, r#"{"state": "done"}"#);
+    }
+
+    #[test]
+    fn poll_stops_on_failure() {
+        let wait = wait_for(
+            "url: http://x/job\ninterval: 0\ntimeout: 5\n\
+             success: {json: {'$.state': done}}\nfailure: {json: {'$.state': failed}}",
+        );
+        let err = wait
+            .poll(replies(vec![
+                Ok((200, r#"{"state": "running"}"#.to_string())),
+                Ok((200, r#"{"state": "failed"}"#.to_string())),
+            ]))
+            .unwrap_err();
+        assert_eq!(err.to_string(), r#"Job failed, 200: {"state": "failed"}"#);
+    }
+
+    #[test]
+    fn poll_times_out() {
+        let wait = wait_for("url: http://x/job\ninterval: 0\ntimeout: 0\nsuccess: {status: 204}");
+        let err = wait.poll(|| Ok((200, String::new()))).unwrap_err();
+        assert_eq!(err.to_string(), "Timed out waiting for http://x/job in 0s");
+    }
+}
@@ -1,3 +1,6 @@
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{Schema, SchemaObject};
+use schemars::JsonSchema;
 use serde::de;
 use serde::{Deserialize, Deserializer};
 use std::fmt;
@@ -35,3 +38,122 @@ where
 
     deserializer.deserialize_any(StringOrVec(PhantomData))
 }
+
+/// Deserialize a value or a list of values, e.g. `200` or `[200, 201]`
+pub fn one_or_seq<'d, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'d>,
+    T: Deserialize<'d>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrSeq<T> {
+        One(T),
+        Seq(Vec<T>),
+    }
+
+    match OneOrSeq::deserialize(deserializer)? {
+        OneOrSeq::One(value) => Ok(vec![value]),
+        OneOrSeq::Seq(values) => Ok(values),
+    }
+}
+
+/// Deserialize an octal number from string, e.g. `"022"`, or an integer
+/// taken as is, e.g. `0o22` or `18`. Note that YAML reads `022` as decimal.
+pub fn octal<'d, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'d>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrInt {
+        String(String),
+        Int(u32),
+    }
+
+    match Option::<StringOrInt>::deserialize(deserializer)? {
+        Some(StringOrInt::String(s)) => {
+            let digits = s.strip_prefix("0o").unwrap_or(&s);
+            u32::from_str_radix(digits, 8)
+                .map(Some)
+                .map_err(|_| de::Error::custom(format!("invalid octal number '{}'", s)))
+        }
+        Some(StringOrInt::Int(n)) => Ok(Some(n)),
+        None => Ok(None),
+    }
+}
+
+/// JSON schema of fields deserialized by `string_or_seq`
+pub fn string_or_seq_schema(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = SchemaObject::default();
+    schema.subschemas().any_of = Some(vec![
+        gen.subschema_for::<String>(),
+        gen.subschema_for::<Vec<String>>(),
+    ]);
+    schema.into()
+}
+
+/// JSON schema of fields deserialized by `one_or_seq`
+pub fn one_or_seq_schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = SchemaObject::default();
+    schema.subschemas().any_of = Some(vec![
+        gen.subschema_for::<T>(),
+        gen.subschema_for::<Vec<T>>(),
+    ]);
+    schema.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Mask {
+        #[serde(default, deserialize_with = "octal")]
+        umask: Option<u32>,
+    }
+
+    fn umask(yaml: &str) -> Result<Option<u32>, serde_yaml::Error> {
+        serde_yaml::from_str::<Mask>(yaml).map(|mask| mask.umask)
+    }
+
+    #[test]
+    fn octal_from_string() {
+        assert_eq!(umask("umask: '022'").unwrap(), Some(0o22));
+        assert_eq!(umask("umask: '0o27'").unwrap(), Some(0o27));
+        assert_eq!(umask("umask: '7'").unwrap(), Some(0o7));
+    }
+
+    #[test]
+    fn octal_from_int_is_taken_as_is() {
+        assert_eq!(umask("umask: 0o27").unwrap(), Some(0o27));
+        assert_eq!(umask("umask: 0o22").unwrap(), Some(0o22));
+        assert_eq!(umask("umask: 18").unwrap(), Some(0o22));
+    }
+
+    #[test]
+    fn octal_absent() {
+        assert_eq!(umask("{}").unwrap(), None);
+        assert_eq!(umask("umask: null").unwrap(), None);
+    }
+
+    #[test]
+    fn octal_invalid() {
+        let err = umask("umask: '089'").unwrap_err();
+        assert!(err.to_string().contains("invalid octal number '089'"));
+        assert!(umask("umask: abc").is_err());
+    }
+
+    #[test]
+    fn string_or_seq_of_yaml() {
+        #[derive(Debug, Deserialize)]
+        struct Files {
+            #[serde(deserialize_with = "string_or_seq")]
+            files: Vec<String>,
+        }
+        let files: Files = serde_yaml::from_str("files: a").unwrap();
+        assert_eq!(files.files, vec!["a"]);
+        let files: Files = serde_yaml::from_str("files: [a, b]").unwrap();
+        assert_eq!(files.files, vec!["a", "b"]);
+    }
+}
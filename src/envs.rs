@@ -1,39 +1,64 @@
 use crate::decode;
+use crate::helper::{is_secret_key, MASK};
+use anyhow::anyhow;
+use schemars::JsonSchema;
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
 use tempfile::{Builder, TempPath};
 
 use env_file_reader::read_file;
 
 /// Env variables, that can be defined in variables, or sourced from env files.
-#[derive(Debug, Clone, Deserialize, Default)]
+#[derive(Debug, Clone, Deserialize, Default, JsonSchema)]
 pub struct Envs {
     #[serde(default, deserialize_with = "decode::string_or_seq")]
+    #[schemars(schema_with = "decode::string_or_seq_schema")]
     env_file: Vec<String>,
     #[serde(default)]
     variables: HashMap<String, String>,
+    /// Output env file, removed once the last clone of envs it is set up
+    /// for is dropped
+    #[serde(skip)]
+    output_file: Option<Arc<TempPath>>,
+    /// Keys of variables from git context, pipeline status or inputs, which
+    /// are shown as is by plan, unless named like secrets
+    #[serde(skip)]
+    public: HashSet<String>,
 }
 
 /// Env output key name
-const OUTPUT_ENV: &str = "ARROW_ENV";
+pub const OUTPUT_ENV: &str = "ARROW_ENV";
 
 impl Envs {
-    /// Create new Envs from hashmap
-    pub fn from_vars(vars: HashMap<String, String>) -> Self {
-        let mut envs = Envs::default();
-        envs.variables = vars;
-        envs
+    /// Create new Envs of git context, pipeline status or inputs, which are
+    /// not secrets
+    pub fn from_context(vars: HashMap<String, String>) -> Self {
+        Envs {
+            public: vars.keys().cloned().collect(),
+            variables: vars,
+            ..Default::default()
+        }
     }
 
-    // Setup output env file, and export it as $ARROW_ENV.
+    /// Env files to source variables from
+    pub fn env_files(&self) -> &[String] {
+        &self.env_file
+    }
+
+    // Setup output env file, and export it as $ARROW_ENV. The file lives as
+    // long as the returned envs, e.g. through the pipeline run.
     pub fn setup_output_env(&self) -> anyhow::Result<Self> {
         let mut envs = self.clone();
-        let env_path = Self::create_output_env_file()?
-            .to_path_buf()
-            .to_string_lossy()
-            .to_string();
+        let output_file = Self::create_output_env_file()?;
+        let env_path = output_file.to_string_lossy().to_string();
         envs.env_file.push(env_path.clone());
         envs.variables.insert(OUTPUT_ENV.to_string(), env_path);
+        envs.public.insert(OUTPUT_ENV.to_string());
+        envs.output_file = Some(Arc::new(output_file));
         Ok(envs)
     }
 
@@ -43,37 +68,264 @@ impl Envs {
         envs.env_file.extend(parent.env_file.clone());
         envs.env_file.extend(self.env_file.clone());
         envs.variables.extend(parent.variables.clone());
-        envs.variables.extend(parent.variables.clone());
+        envs.variables.extend(self.variables.clone());
+        envs.public.extend(parent.public.iter().cloned());
+        envs.public.extend(self.public.iter().cloned());
+        // overridden by variables of own
+        envs.public
+            .retain(|key| self.public.contains(key) || !self.variables.contains_key(key));
         envs
     }
 
-    /// Render template string in currrent env
-    pub fn render(&self, template: &String) -> anyhow::Result<String> {
-        let vars = self.build_env()?;
-        let mut template = template.clone();
-        for (key, value) in vars.iter() {
-            template = template.replace(&format!("${}", key), value);
-        }
-        Ok(template)
-    }
-
     /// Build and return environment variables from env_file and environment
     pub fn build_env(&self) -> anyhow::Result<HashMap<String, String>> {
         let mut vars = HashMap::new();
         for file in &self.env_file {
             let path = std::path::Path::new(file);
-            if path.exists() {
-                let variables = read_file(file)?;
-                vars.extend(variables);
+            if !path.exists() {
+                continue;
+            }
+            if self.variables.get(OUTPUT_ENV) == Some(file) {
+                vars.extend(read_output_file(path)?);
+            } else {
+                vars.extend(read_file(file)?);
             }
         }
         vars.extend(self.variables.clone());
         Ok(vars)
     }
 
+    /// Variables as by `build_env`, with values masked unless they come
+    /// from git context, pipeline status or inputs, so that plan shows no
+    /// secrets of env files or `variables`. Empty values are left as is.
+    pub fn plan_env(&self) -> anyhow::Result<HashMap<String, String>> {
+        let mut vars = self.build_env()?;
+        for (key, value) in vars.iter_mut() {
+            if !value.is_empty() && (!self.public.contains(key) || is_secret_key(key)) {
+                *value = MASK.to_string();
+            }
+        }
+        Ok(vars)
+    }
+
+    /// Envs of masked variables by `plan_env`, to render templates for plan
+    pub fn masked(&self) -> anyhow::Result<Envs> {
+        Ok(Envs {
+            variables: self.plan_env()?,
+            public: self.public.clone(),
+            ..Default::default()
+        })
+    }
+
+    /// Append variables to output env file of `$ARROW_ENV` in vars, so
+    /// they are visible to later actions
+    pub fn write_output(
+        vars: &HashMap<String, String>,
+        outputs: &[(String, String)],
+    ) -> anyhow::Result<()> {
+        let path = vars
+            .get(OUTPUT_ENV)
+            .ok_or_else(|| anyhow!("${} is not set", OUTPUT_ENV))?;
+        let mut file = OpenOptions::new().append(true).open(path)?;
+        for (key, value) in outputs {
+            let quoted = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            writeln!(file, "{}=\"{}\"", key, quoted)?;
+        }
+        Ok(())
+    }
+
     /// Create temporary file for output envs
     fn create_output_env_file() -> anyhow::Result<TempPath> {
         let file = Builder::new().prefix("arrow-").suffix(".env").tempfile()?;
-        return Ok(file.into_temp_path());
+        Ok(file.into_temp_path())
+    }
+}
+
+/// Read output env file, in lines of `KEY=value`, `KEY='value'` taken as
+/// is, or `KEY="value"` with `\\`, `\"`, `\n` and `\$` escaped, as outputs
+/// are written. Quoted values may span lines, `#` starts a comment line.
+fn read_output_file(path: &Path) -> anyhow::Result<HashMap<String, String>> {
+    let content = std::fs::read_to_string(path)?;
+    let unterminated = |key: &str| anyhow!("Unterminated value of {} in {}", key, path.display());
+    let mut vars = HashMap::new();
+    let mut rest = content.as_str();
+    while !rest.is_empty() {
+        let (line, next) = rest.split_once('\n').unwrap_or((rest, ""));
+        let eq = match line.find('=') {
+            Some(eq) if !line.trim_start().starts_with('#') => eq,
+            _ => {
+                rest = next;
+                continue;
+            }
+        };
+        let key = line[..eq].trim();
+        let key = key.strip_prefix("export ").unwrap_or(key).trim();
+        // quoted value may span lines, so it is parsed from the rest
+        let value_src = rest[eq + 1..].trim_start_matches([' ', '\t']);
+        let (value, after) = match value_src.chars().next() {
+            Some('"') => {
+                let mut value = String::new();
+                let mut chars = value_src.char_indices().skip(1);
+                let end = loop {
+                    match chars.next().ok_or_else(|| unterminated(key))? {
+                        (i, '"') => break i,
+                        (_, '\\') => match chars.next().ok_or_else(|| unterminated(key))? {
+                            (_, 'n') => value.push('\n'),
+                            (_, c @ ('\\' | '"' | '$')) => value.push(c),
+                            (_, c) => {
+                                value.push('\\');
+                                value.push(c);
+                            }
+                        },
+                        (_, c) => value.push(c),
+                    }
+                };
+                (value, skip_line(&value_src[end + 1..]))
+            }
+            Some('\'') => {
+                let end = value_src[1..].find('\'').ok_or_else(|| unterminated(key))?;
+                (
+                    value_src[1..end + 1].to_string(),
+                    skip_line(&value_src[end + 2..]),
+                )
+            }
+            _ => (line[eq + 1..].trim().to_string(), next),
+        };
+        vars.insert(key.to_string(), value);
+        rest = after;
+    }
+    Ok(vars)
+}
+
+/// Rest of content after the current line
+fn skip_line(content: &str) -> &str {
+    content.split_once('\n').map_or("", |(_, next)| next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn output_path(envs: &Envs) -> PathBuf {
+        PathBuf::from(&envs.build_env().unwrap()[OUTPUT_ENV])
+    }
+
+    #[test]
+    fn output_env_lives_with_envs() {
+        let envs = Envs::default().setup_output_env().unwrap();
+        let path = output_path(&envs);
+        assert!(path.is_file());
+        let cloned = envs.clone();
+        drop(envs);
+        assert!(path.is_file());
+        drop(cloned);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn outputs_round_trip() {
+        let envs = Envs::default().setup_output_env().unwrap();
+        let values = [
+            "plain",
+            "with space",
+            "multi\nline",
+            "back\\slash",
+            "literal \\n and \\\"",
+            "$HOME and ${PATH}",
+            "quote \" inside",
+            "it's",
+            "it's `both`",
+            "",
+        ];
+        let outputs: Vec<(String, String)> = values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| (format!("OUT_{}", i), value.to_string()))
+            .collect();
+        let vars = envs.build_env().unwrap();
+        Envs::write_output(&vars, &outputs[..5]).unwrap();
+        Envs::write_output(&vars, &outputs[5..]).unwrap();
+        let vars = envs.build_env().unwrap();
+        for (key, value) in &outputs {
+            assert_eq!(&vars[key], value, "{}", key);
+        }
+    }
+
+    #[test]
+    fn read_lines_written_by_scripts() {
+        let envs = Envs::default().setup_output_env().unwrap();
+        let path = output_path(&envs);
+        std::fs::write(
+            &path,
+            "A=1\nexport B = two words \n# C=3\n\nD='x\ny'\nE=\"a\\\\b \\t\" # note\nF=\n",
+        )
+        .unwrap();
+        let vars = read_output_file(&path).unwrap();
+        assert_eq!(vars.len(), 5);
+        assert_eq!(vars["A"], "1");
+        assert_eq!(vars["B"], "two words");
+        assert_eq!(vars["D"], "x\ny");
+        assert_eq!(vars["E"], "a\\b \\t");
+        assert_eq!(vars["F"], "");
+
+        std::fs::write(&path, "A=\"open\n").unwrap();
+        let err = read_output_file(&path).unwrap_err();
+        assert!(err.to_string().starts_with("Unterminated value of A"));
+    }
+
+    #[test]
+    fn write_output_requires_output_env() {
+        let err = Envs::write_output(&HashMap::new(), &[]).unwrap_err();
+        assert_eq!(err.to_string(), "$ARROW_ENV is not set");
+    }
+
+    #[test]
+    fn inherit_overrides_parent() {
+        let mut parent = HashMap::new();
+        parent.insert("A".to_string(), "parent".to_string());
+        parent.insert("B".to_string(), "parent".to_string());
+        let mut child = HashMap::new();
+        child.insert("B".to_string(), "child".to_string());
+        let vars = Envs::from_context(child)
+            .inherit(&Envs::from_context(parent))
+            .build_env()
+            .unwrap();
+        assert_eq!(vars["A"], "parent");
+        assert_eq!(vars["B"], "child");
+    }
+
+    #[test]
+    fn plan_env_masks_all_but_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_file = dir.path().join("secrets.env");
+        std::fs::write(&env_file, "DB_URL=postgres://u:p@db\nREGION=eu\n").unwrap();
+        let context = Envs::from_context(HashMap::from([
+            ("BRANCH".to_string(), "main".to_string()),
+            ("REV_SHORT".to_string(), "abc1234".to_string()),
+            ("PUSH_OPTION_TOKEN".to_string(), "t0k3n".to_string()),
+            ("PUSHER".to_string(), String::new()),
+        ]));
+        let pipeline: Envs = serde_yaml::from_str(&format!(
+            "{{env_file: '{}', variables: {{STAGE: prod, BRANCH: override}}}}",
+            env_file.display()
+        ))
+        .unwrap();
+        let envs = pipeline.inherit(&context).setup_output_env().unwrap();
+        let vars = envs.plan_env().unwrap();
+        assert_eq!(vars["REV_SHORT"], "abc1234");
+        assert!(vars[OUTPUT_ENV].starts_with('/'));
+        assert_eq!(vars["PUSHER"], "");
+        for key in ["PUSH_OPTION_TOKEN", "DB_URL", "REGION", "STAGE", "BRANCH"] {
+            assert_eq!(vars[key], MASK, "{}", key);
+        }
+
+        // masked envs stay masked through inherit and build
+        let action = Envs::default().inherit(&envs.masked().unwrap());
+        assert_eq!(action.build_env().unwrap(), vars);
+        assert_eq!(action.plan_env().unwrap(), vars);
     }
 }
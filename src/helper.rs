@@ -1,13 +1,87 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
 use std::time::Duration;
 
-pub fn path_to_string(path: &PathBuf, default: &str) -> String {
+pub fn path_to_string(path: &Path, default: &str) -> String {
     match path.to_str() {
         Some(s) => s.to_string(),
         None => default.to_string(),
     }
 }
 
+/// Normalize path lexically, by resolving `.` and `..` components
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// Whether path is relative and stays inside the dir it is joined to
+pub fn is_inside(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Quote value in single quotes for POSIX shell
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Whether key is valid as env name in shell
+pub fn is_env_name(key: &str) -> bool {
+    let mut chars = key.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Mask of secret values shown by plan
+pub const MASK: &str = "***";
+
+/// Whether key is named like a secret, e.g. `API_TOKEN` or `DEPLOY_KEY`
+pub fn is_secret_key(key: &str) -> bool {
+    let key = key.to_uppercase();
+    [
+        "SECRET",
+        "TOKEN",
+        "PASSWORD",
+        "PASSWD",
+        "CREDENTIAL",
+        "PRIVATE",
+    ]
+    .iter()
+    .any(|word| key.contains(word))
+        || key == "KEY"
+        || key.ends_with("_KEY")
+}
+
+/// Print variables in `KEY=value` lines, sorted by key. Values should be
+/// masked by `Envs::plan_env` already, as plan is shown to the pusher.
+pub fn print_vars(vars: &HashMap<String, String>) {
+    for line in var_lines(vars) {
+        println!("  {}", line);
+    }
+}
+
+/// Lines of `KEY=value` sorted by key
+fn var_lines(vars: &HashMap<String, String>) -> Vec<String> {
+    let mut keys: Vec<&String> = vars.keys().collect();
+    keys.sort();
+    keys.into_iter()
+        .map(|key| format!("{}={}", key, vars[key]))
+        .collect()
+}
+
 pub fn format_duration(du: Duration) -> String {
     let ms = du.as_millis();
     if ms < 1000 {
@@ -24,5 +98,60 @@ pub fn format_duration(du: Duration) -> String {
     }
     let h = m / 60;
     let m = m % 60;
-    return format!("{}h{}m{}s", h, m, s);
+    format!("{}h{}m{}s", h, m, s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_for_shell() {
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("$(id)\n`id`"), "'$(id)\n`id`'");
+    }
+
+    #[test]
+    fn env_names() {
+        for key in ["A", "_A", "REV_SHORT", "a1"] {
+            assert!(is_env_name(key), "{}", key);
+        }
+        for key in ["", "1ST", "A-B", "A.B", "A B", "É"] {
+            assert!(!is_env_name(key), "{}", key);
+        }
+    }
+
+    #[test]
+    fn vars_in_lines() {
+        let mut vars = HashMap::new();
+        vars.insert("TOKEN".to_string(), MASK.to_string());
+        vars.insert("EMPTY".to_string(), String::new());
+        vars.insert("BRANCH".to_string(), "main".to_string());
+        assert_eq!(var_lines(&vars), vec!["BRANCH=main", "EMPTY=", "TOKEN=***"]);
+    }
+
+    #[test]
+    fn secret_keys() {
+        for key in [
+            "API_TOKEN",
+            "github_token",
+            "SECRET",
+            "DB_PASSWORD",
+            "DEPLOY_KEY",
+            "KEY",
+        ] {
+            assert!(is_secret_key(key), "{}", key);
+        }
+        for key in [
+            "BRANCH",
+            "REV_SHORT",
+            "KEYBOARD",
+            "MONKEY_NAME",
+            "PUSH_OPTION_ENV",
+        ] {
+            assert!(!is_secret_key(key), "{}", key);
+        }
+    }
 }
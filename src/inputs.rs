@@ -0,0 +1,269 @@
+use anyhow::anyhow;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::helper::is_env_name;
+
+/// Inputs of pipeline by name, given by `--param` of `arrow trigger`
+pub type Inputs = BTreeMap<String, InputSpec>;
+
+/// Input of pipeline, exported as `INPUT_<NAME>`
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct InputSpec {
+    description: Option<String>,
+    #[serde(default, rename = "type")]
+    kind: InputType,
+    /// Value if not given, the input is required without it
+    default: Option<Value>,
+    /// Allowed values of `choice`
+    #[serde(default)]
+    options: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum InputType {
+    #[default]
+    String,
+    Number,
+    Boolean,
+    Choice,
+}
+
+impl InputSpec {
+    /// Check value by type, returns normalized value
+    fn check(&self, name: &str, value: &str) -> anyhow::Result<String> {
+        match self.kind {
+            InputType::String => Ok(value.to_string()),
+            InputType::Number => match value.trim().parse::<f64>() {
+                Ok(number) if number.is_finite() => Ok(value.trim().to_string()),
+                _ => Err(anyhow!(
+                    "Input {} should be a number, got '{}'",
+                    name,
+                    value
+                )),
+            },
+            InputType::Boolean => match value.to_lowercase().as_str() {
+                "true" => Ok("true".to_string()),
+                "false" => Ok("false".to_string()),
+                _ => Err(anyhow!(
+                    "Input {} should be true or false, got '{}'",
+                    name,
+                    value
+                )),
+            },
+            InputType::Choice => {
+                if self.options.iter().any(|option| option == value) {
+                    Ok(value.to_string())
+                } else {
+                    Err(anyhow!(
+                        "Input {} should be one of {:?}, got '{}'",
+                        name,
+                        self.options,
+                        value
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Default value as string, if any
+    fn default_value(&self, name: &str) -> anyhow::Result<Option<String>> {
+        match self.default {
+            None => Ok(None),
+            Some(Value::String(ref value)) => Ok(Some(value.clone())),
+            Some(Value::Number(ref value)) => Ok(Some(value.to_string())),
+            Some(Value::Bool(value)) => Ok(Some(value.to_string())),
+            Some(_) => Err(anyhow!("Default of input {} should be a scalar", name)),
+        }
+    }
+}
+
+/// Env key of input, e.g. `INPUT_DRY_RUN` of `dry-run`
+fn env_key(name: &str) -> String {
+    format!("INPUT_{}", name.to_uppercase().replace('-', "_"))
+}
+
+/// Check names, options and defaults of inputs
+pub fn validate(inputs: &Inputs) -> anyhow::Result<()> {
+    for (name, spec) in inputs {
+        if !is_env_name(&env_key(name)) {
+            return Err(anyhow!("Invalid input name '{}'", name));
+        }
+        if matches!(spec.kind, InputType::Choice) && spec.options.is_empty() {
+            return Err(anyhow!("Input {} of choice should have options", name));
+        }
+        if let Some(value) = spec.default_value(name)? {
+            spec.check(name, &value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Resolve env of inputs from params. Without params, e.g. on push, only
+/// inputs with default are set, otherwise all inputs are required to be
+/// given or have default, and unknown params are rejected.
+pub fn resolve(
+    inputs: &Inputs,
+    params: Option<&HashMap<String, String>>,
+) -> anyhow::Result<HashMap<String, String>> {
+    validate(inputs)?;
+    if let Some(params) = params {
+        for name in params.keys() {
+            if !inputs.contains_key(name) {
+                let known: Vec<&String> = inputs.keys().collect();
+                return Err(anyhow!("Unknown input {}, expect one of {:?}", name, known));
+            }
+        }
+    }
+    let mut vars = HashMap::new();
+    for (name, spec) in inputs {
+        let value = match params.and_then(|params| params.get(name)) {
+            Some(value) => Some(value.clone()),
+            None => spec.default_value(name)?,
+        };
+        match value {
+            Some(value) => {
+                vars.insert(env_key(name), spec.check(name, &value)?);
+            }
+            None if params.is_some() => {
+                return Err(match spec.description {
+                    Some(ref description) => {
+                        anyhow!("Input {} is required, {}", name, description)
+                    }
+                    None => anyhow!("Input {} is required", name),
+                });
+            }
+            None => {}
+        }
+    }
+    Ok(vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs(yaml: &str) -> Inputs {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    const INPUTS: &str = "
+        target: {type: choice, options: [staging, prod], default: staging}
+        dry-run: {type: boolean, default: false}
+        replicas: {type: number}
+        tag: {description: image tag to deploy}
+    ";
+
+    #[test]
+    fn resolve_params_and_defaults() {
+        let vars = resolve(
+            &inputs(INPUTS),
+            Some(&params(&[
+                ("target", "prod"),
+                ("dry-run", "TRUE"),
+                ("replicas", " 3 "),
+                ("tag", "v1"),
+            ])),
+        )
+        .unwrap();
+        assert_eq!(
+            vars,
+            params(&[
+                ("INPUT_TARGET", "prod"),
+                ("INPUT_DRY_RUN", "true"),
+                ("INPUT_REPLICAS", "3"),
+                ("INPUT_TAG", "v1"),
+            ])
+        );
+
+        let vars = resolve(
+            &inputs(INPUTS),
+            Some(&params(&[("replicas", "1.5"), ("tag", "")])),
+        )
+        .unwrap();
+        assert_eq!(vars["INPUT_TARGET"], "staging");
+        assert_eq!(vars["INPUT_DRY_RUN"], "false");
+        assert_eq!(vars["INPUT_REPLICAS"], "1.5");
+        assert_eq!(vars["INPUT_TAG"], "");
+    }
+
+    #[test]
+    fn only_defaults_without_params() {
+        let vars = resolve(&inputs(INPUTS), None).unwrap();
+        assert_eq!(
+            vars,
+            params(&[("INPUT_TARGET", "staging"), ("INPUT_DRY_RUN", "false")])
+        );
+    }
+
+    #[test]
+    fn missing_required_input() {
+        let err = resolve(&inputs(INPUTS), Some(&params(&[("replicas", "1")]))).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Input tag is required, image tag to deploy"
+        );
+        let err = resolve(&inputs(INPUTS), Some(&params(&[("tag", "v1")]))).unwrap_err();
+        assert_eq!(err.to_string(), "Input replicas is required");
+    }
+
+    #[test]
+    fn unknown_param() {
+        let err = resolve(&inputs(INPUTS), Some(&params(&[("tags", "v1")]))).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown input tags, expect one of [\"dry-run\", \"replicas\", \"tag\", \"target\"]"
+        );
+    }
+
+    #[test]
+    fn invalid_values() {
+        let resolve_with = |pairs: &[(&str, &str)]| {
+            let mut given = params(&[("replicas", "1"), ("tag", "v1")]);
+            given.extend(params(pairs));
+            resolve(&inputs(INPUTS), Some(&given))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            resolve_with(&[("dry-run", "yes")]),
+            "Input dry-run should be true or false, got 'yes'"
+        );
+        assert_eq!(
+            resolve_with(&[("replicas", "three")]),
+            "Input replicas should be a number, got 'three'"
+        );
+        assert_eq!(
+            resolve_with(&[("replicas", "inf")]),
+            "Input replicas should be a number, got 'inf'"
+        );
+        assert_eq!(
+            resolve_with(&[("target", "dev")]),
+            "Input target should be one of [\"staging\", \"prod\"], got 'dev'"
+        );
+    }
+
+    #[test]
+    fn invalid_inputs() {
+        let err = resolve(&inputs("env: {type: choice}"), None).unwrap_err();
+        assert_eq!(err.to_string(), "Input env of choice should have options");
+        let err = resolve(&inputs("'dry run': {}"), None).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid input name 'dry run'");
+        let err = resolve(&inputs("n: {type: number, default: many}"), None).unwrap_err();
+        assert_eq!(err.to_string(), "Input n should be a number, got 'many'");
+        let err = resolve(&inputs("tags: {default: [a, b]}"), None).unwrap_err();
+        assert_eq!(err.to_string(), "Default of input tags should be a scalar");
+        assert!(serde_yaml::from_str::<Inputs>("n: {type: integer}").is_err());
+    }
+}
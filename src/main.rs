@@ -2,14 +2,25 @@ mod actions;
 mod decode;
 mod envs;
 mod helper;
+mod inputs;
+mod matcher;
+mod output;
 mod pipeline;
 mod repo;
-use anyhow::anyhow;
+mod source;
+use anyhow::{anyhow, Context as _};
 use clap::{Parser, Subcommand};
-use pipeline::Pipelines;
+use pipeline::{Pipelines, PIPELINE_DIR};
 use repo::Context;
+use source::LocalSource;
+use std::collections::HashMap;
 use std::env;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Env to toggle dry run in hook mode
+const DRY_RUN_ENV: &str = "ARROW_DRY_RUN";
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
@@ -23,17 +34,159 @@ enum Command {
     /// Run pipeline from file or directory on current HEAD
     Run {
         /// Path to pipeline file or directory
+        #[arg(default_value = PIPELINE_DIR)]
+        path: String,
+        /// Print what would run, without executing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Push option as by `git push -o`, in `key` or `key=value`
+        #[arg(short = 'o', long = "push-option")]
+        push_options: Vec<String>,
+    },
+    /// Validate pipeline files
+    Validate {
+        /// Path to pipeline file or directory
+        #[arg(default_value = PIPELINE_DIR)]
         path: String,
     },
+    /// Run pipeline on demand, on ref of repo
+    Trigger {
+        /// Path to repo, bare or working copy
+        repo: String,
+        /// Name of pipeline
+        pipeline: String,
+        /// Branch, tag or revision to run on
+        #[arg(long = "ref", default_value = "HEAD")]
+        reference: String,
+        /// Input of pipeline, in `KEY=VALUE`
+        #[arg(short, long = "param")]
+        params: Vec<String>,
+        /// Print what would run, without executing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Print JSON schema of pipeline file
+    Schema,
 }
 
 fn main() -> anyhow::Result<()> {
-    if let Ok(_) = env::var("GIT_DIR") {
+    if env::var("GIT_DIR").is_ok() {
         return run_hook();
+    }
+    let cli = Cli::parse();
+    match cli.command {
+        Command::Run {
+            path,
+            dry_run,
+            push_options,
+        } => run_local(&path, dry_run, push_options),
+        Command::Validate { path } => validate(&path),
+        Command::Trigger {
+            repo,
+            pipeline,
+            reference,
+            params,
+            dry_run,
+        } => trigger(&repo, &pipeline, &reference, &params, dry_run),
+        Command::Schema => print_schema(),
+    }
+}
+
+/// Run pipelines at path on current HEAD of working copy
+fn run_local(path: &str, dry_run: bool, push_options: Vec<String>) -> anyhow::Result<()> {
+    let ctx = Context::resolve_local(push_options)?;
+    let pipelines = Pipelines::load(&LocalSource, path)?;
+    if dry_run {
+        return pipelines.plan(&ctx);
+    }
+    pipelines.run(&ctx)
+}
+
+/// Run pipeline of name on ref of repo, as if it was pushed
+fn trigger(
+    repo: &str,
+    name: &str,
+    reference: &str,
+    params: &[String],
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let mut inputs = HashMap::new();
+    for param in params {
+        let (key, value) = param
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid param '{}', expect KEY=VALUE", param))?;
+        inputs.insert(key.to_string(), value.to_string());
+    }
+    let ctx = Context::resolve_manual(repo, reference)?;
+    let pipelines = Pipelines::load(&ctx, PIPELINE_DIR)?;
+    pipelines.trigger(&ctx, name, &inputs, dry_run)
+}
+
+/// Validate pipeline files at path, report all problems found
+fn validate(path: &str) -> anyhow::Result<()> {
+    let files = Pipelines::list_pipeline_files(&LocalSource, path)?;
+    if files.is_empty() {
+        return Err(anyhow!("No pipeline file found at {}", path));
+    }
+    let root = repo_root(path);
+    let mut errors = 0;
+    for file in &files {
+        let result = Pipelines::parse_file(&LocalSource, file).and_then(|pipeline| {
+            pipeline
+                .validate(&root)
+                .with_context(|| format!("Invalid pipeline file {}", file))
+        });
+        match result {
+            Ok(warnings) => {
+                println!("ok: {}", file);
+                for warning in warnings {
+                    println!("  warning: {}", warning);
+                }
+            }
+            Err(err) => {
+                errors += 1;
+                println!("error: {:#}", err);
+            }
+        }
+    }
+    if errors > 0 {
+        return Err(anyhow!(
+            "{} of {} pipeline files invalid",
+            errors,
+            files.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Root of repo that owns path, or current dir if it is not in a repo
+fn repo_root(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    let dir = if path.is_dir() {
+        path
     } else {
-        let cli = Cli::parse();
+        path.parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+    };
+    let output = process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "--show-toplevel"])
+        .stderr(process::Stdio::null())
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            PathBuf::from(String::from_utf8_lossy(&output.stdout).trim())
+        }
+        _ => PathBuf::from("."),
     }
+}
 
+/// Print JSON schema of pipeline file, for editor completion
+fn print_schema() -> anyhow::Result<()> {
+    let schema = pipeline::schema();
+    println!("{}", serde_json::to_string_pretty(&schema)?);
     Ok(())
 }
 
@@ -48,8 +201,44 @@ fn run_hook() -> anyhow::Result<()> {
             input
         ));
     }
-    let ctx = Context::resolve_on_hook(args[2].clone(), args[0].clone(), args[1].clone())?;
-    let mut pipelines = Pipelines::new();
-    pipelines.run(ctx)?;
-    Ok(())
+    let ctx = Context::resolve_on_hook(
+        args[2].clone(),
+        args[0].clone(),
+        args[1].clone(),
+        push_options()?,
+    )?;
+    if ctx.is_deletion() {
+        println!("{} deleted, no pipeline to run", ctx.refname);
+        return Ok(());
+    }
+    let pipelines = Pipelines::load(&ctx, PIPELINE_DIR)?;
+    if is_dry_run() {
+        return pipelines.plan(&ctx);
+    }
+    pipelines.run(&ctx)
+}
+
+/// Options of `git push -o`, passed to hook by git in env, when the repo
+/// has `receive.advertisePushOptions` enabled
+fn push_options() -> anyhow::Result<Vec<String>> {
+    let count = match env::var("GIT_PUSH_OPTION_COUNT") {
+        Ok(count) => count
+            .parse::<usize>()
+            .with_context(|| format!("Invalid GIT_PUSH_OPTION_COUNT '{}'", count))?,
+        Err(_) => return Ok(Vec::new()),
+    };
+    (0..count)
+        .map(|n| {
+            let key = format!("GIT_PUSH_OPTION_{}", n);
+            env::var(&key).with_context(|| format!("{} not found", key))
+        })
+        .collect()
+}
+
+/// Whether dry run is toggled by env in hook mode
+fn is_dry_run() -> bool {
+    match env::var(DRY_RUN_ENV) {
+        Ok(value) => matches!(value.to_lowercase().as_str(), "1" | "true" | "yes"),
+        Err(_) => false,
+    }
 }
@@ -0,0 +1,163 @@
+use anyhow::Context as _;
+use glob::{MatchOptions, Pattern};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{de, Deserialize, Deserializer};
+
+use crate::decode;
+
+/// As patterns were matched before, `*` matches across `/` too, so that
+/// `*.txt` matches `sub/c.txt`
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: false,
+    require_literal_leading_dot: false,
+};
+
+/// List of glob patterns compiled upon deserialization, for matching file
+/// paths relative to repo root.
+///
+/// Pattern prefixed with `!` excludes paths matched by previous patterns,
+/// the last matching pattern wins. If the first pattern is a negation, all
+/// paths are matched initially, e.g. `!docs/**` matches all but docs.
+#[derive(Debug, Default, Clone)]
+pub struct GlobSet {
+    patterns: Vec<GlobPattern>,
+}
+
+#[derive(Debug, Clone)]
+struct GlobPattern {
+    pattern: Pattern,
+    negated: bool,
+}
+
+impl GlobSet {
+    pub fn new(patterns: &[String]) -> anyhow::Result<Self> {
+        let mut set = GlobSet::default();
+        for pat in patterns {
+            let (negated, glob) = match pat.strip_prefix('!') {
+                Some(glob) => (true, glob),
+                None => (false, pat.as_str()),
+            };
+            let pattern =
+                Pattern::new(glob).with_context(|| format!("Invalid glob pattern '{}'", pat))?;
+            set.patterns.push(GlobPattern { pattern, negated });
+        }
+        Ok(set)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Whether path is matched, always false if set is empty
+    pub fn matches(&self, path: &str) -> bool {
+        let mut matched = match self.patterns.first() {
+            Some(first) => first.negated,
+            None => return false,
+        };
+        for pat in &self.patterns {
+            if matched != pat.negated {
+                // state won't change by this pattern
+                continue;
+            }
+            if pat.pattern.matches_with(path, MATCH_OPTIONS) {
+                matched = !pat.negated;
+            }
+        }
+        matched
+    }
+}
+
+impl<'de> Deserialize<'de> for GlobSet {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let patterns = decode::string_or_seq(deserializer)?;
+        GlobSet::new(&patterns).map_err(|err| de::Error::custom(format!("{:#}", err)))
+    }
+}
+
+impl JsonSchema for GlobSet {
+    fn schema_name() -> String {
+        "GlobSet".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        decode::string_or_seq_schema(gen)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn globs(patterns: &[&str]) -> GlobSet {
+        let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        GlobSet::new(&patterns).unwrap()
+    }
+
+    #[test]
+    fn empty_set_matches_nothing() {
+        let set = globs(&[]);
+        assert!(set.is_empty());
+        assert!(!set.matches("a.txt"));
+    }
+
+    #[test]
+    fn star_matches_across_dirs() {
+        let set = globs(&["*.txt"]);
+        assert!(set.matches("a.txt"));
+        assert!(set.matches("sub/c.txt"));
+        assert!(!set.matches("a.md"));
+    }
+
+    #[test]
+    fn double_star() {
+        let set = globs(&["src/**/*.rs"]);
+        assert!(set.matches("src/main.rs"));
+        assert!(set.matches("src/actions/ssh/native.rs"));
+        assert!(!set.matches("tests/main.rs"));
+    }
+
+    #[test]
+    fn negation_excludes_previous_matches() {
+        let set = globs(&["src/**", "!src/**/*.md"]);
+        assert!(set.matches("src/main.rs"));
+        assert!(!set.matches("src/docs/README.md"));
+        assert!(!set.matches("README.md"));
+    }
+
+    #[test]
+    fn last_matching_pattern_wins() {
+        let set = globs(&["docs/**", "!docs/**", "docs/api/**"]);
+        assert!(set.matches("docs/api/index.md"));
+        assert!(!set.matches("docs/guide.md"));
+    }
+
+    #[test]
+    fn leading_negation_matches_all_others() {
+        let set = globs(&["!docs/**"]);
+        assert!(set.matches("src/main.rs"));
+        assert!(!set.matches("docs/guide.md"));
+    }
+
+    #[test]
+    fn invalid_pattern_is_error() {
+        let err = GlobSet::new(&["src/[".to_string()]).unwrap_err();
+        assert!(format!("{:#}", err).contains("Invalid glob pattern 'src/['"));
+    }
+
+    #[test]
+    fn deserialize_string_or_list() {
+        let set: GlobSet = serde_yaml::from_str("'*.rs'").unwrap();
+        assert!(set.matches("main.rs"));
+        let set: GlobSet = serde_yaml::from_str("['*.rs', '!build.rs']").unwrap();
+        assert!(set.matches("main.rs"));
+        assert!(!set.matches("build.rs"));
+        let err = serde_yaml::from_str::<GlobSet>("'a[b'").unwrap_err();
+        assert!(err.to_string().contains("Invalid glob pattern"));
+    }
+}
@@ -0,0 +1,39 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Max lines of output kept, for notifications on failure
+const MAX_LINES: usize = 50;
+
+/// Tail of output of the running action
+static TAIL: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Print line of action output to stdout, and keep it in tail
+pub fn print_out(line: &str) {
+    println!("  {}", line);
+    record(line);
+}
+
+/// Print line of action output to stderr, and keep it in tail
+pub fn print_err(line: &str) {
+    eprintln!("  {}", line);
+    record(line);
+}
+
+fn record(line: &str) {
+    let mut tail = TAIL.lock().unwrap_or_else(|err| err.into_inner());
+    if tail.len() >= MAX_LINES {
+        tail.pop_front();
+    }
+    tail.push_back(line.to_string());
+}
+
+/// Clear tail, before an action starts
+pub fn clear() {
+    TAIL.lock().unwrap_or_else(|err| err.into_inner()).clear();
+}
+
+/// Lines of output kept so far
+pub fn tail() -> Vec<String> {
+    let tail = TAIL.lock().unwrap_or_else(|err| err.into_inner());
+    tail.iter().cloned().collect()
+}
@@ -1,13 +1,42 @@
-use anyhow::Context as _;
+use anyhow::{anyhow, Context as _};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::RootSchema;
+use schemars::JsonSchema;
 use serde::Deserialize;
 use serde_yaml as yaml;
-use std::fs::File;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Instant;
 
-use crate::actions::{Action, IAction};
+use crate::actions::{IAction, RunOn, Step};
 use crate::decode;
 use crate::envs::Envs;
+use crate::helper::{format_duration, normalize_path, print_vars};
+use crate::inputs::{self, Inputs};
+use crate::matcher::GlobSet;
+use crate::output;
 use crate::repo::Context;
+use crate::source::Source;
+
+/// Directory of pipeline definitions, relative to repo root
+pub const PIPELINE_DIR: &str = ".arrow";
+
+/// Key to include fragment files into a pipeline definition
+const INCLUDE_KEY: &str = "include";
+
+/// Max depth of nested includes
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Env of pipeline name, exported to actions
+pub const PIPELINE_ENV: &str = "ARROW_PIPELINE";
+/// Env of pipeline status so far, `success` or `failure`
+pub const STATUS_ENV: &str = "ARROW_STATUS";
+/// Env of time elapsed since the pipeline started
+pub const DURATION_ENV: &str = "ARROW_DURATION";
+/// Env of name of the first failed action, if any
+pub const FAILED_ACTION_ENV: &str = "ARROW_FAILED_ACTION";
+/// Env of tail of output and error of the first failed action, if any
+pub const FAILED_OUTPUT_ENV: &str = "ARROW_FAILED_OUTPUT";
 
 #[derive(Debug, Default)]
 pub struct Pipelines {
@@ -15,73 +44,294 @@ pub struct Pipelines {
 }
 
 impl Pipelines {
-    pub fn new() -> Self {
-        Pipelines::default()
+    /// Load pipelines defined in dir of source
+    pub fn load(source: &dyn Source, dir: &str) -> anyhow::Result<Self> {
+        let pipelines = Self::parse_pipelines(source, dir)?;
+        Ok(Pipelines { pipelines })
     }
 
-    pub fn parse_pipelines(dir: &str) -> anyhow::Result<Vec<Pipeline>> {
+    /// Parse pipeline definitions from the tree of pushed revision, so that
+    /// they are exactly what was pushed, whatever is checked out.
+    ///
+    /// Only `*.yml` and `*.yaml` files are loaded, from dir and its sub
+    /// dirs. Files prefixed with `_` are fragments, which are not pipelines
+    /// on their own, but can be included by pipelines.
+    pub fn parse_pipelines(source: &dyn Source, dir: &str) -> anyhow::Result<Vec<Pipeline>> {
         let mut pipelines = Vec::new();
-        let pipeline_dir = PathBuf::from(dir);
-        if !pipeline_dir.exists() {
-            return Ok(pipelines);
-        }
-        for entry in std::fs::read_dir(pipeline_dir)
-            .with_context(|| format!("Failed to read pipeline definitions from {}", dir))?
-        {
-            let path = entry?.path();
-            if path.is_file() {
-                let pipeline = Self::parse_path(&path)?;
-                pipelines.push(pipeline);
-            }
+        let files = Self::list_pipeline_files(source, dir)?;
+        for path in files {
+            let pipeline = Self::parse_file(source, &path)?;
+            pipelines.push(pipeline);
         }
         Ok(pipelines)
     }
 
-    fn parse_path(path: &PathBuf) -> anyhow::Result<Pipeline> {
-        let name = path.display();
-        let file = File::open(path).with_context(|| format!("Failed to open file {}", name))?;
-        let pipeline: Pipeline = yaml::from_reader(file)
-            .with_context(|| format!("Failed to parse pipeline file {}", name))?;
-        Ok(pipeline)
+    /// List pipeline files in dir, fragments are excluded
+    pub fn list_pipeline_files(source: &dyn Source, dir: &str) -> anyhow::Result<Vec<String>> {
+        let files = source
+            .list_files(dir)
+            .with_context(|| format!("Failed to read pipeline definitions from {}", dir))?;
+        Ok(files
+            .into_iter()
+            .filter(|path| Self::is_pipeline_file(path))
+            .collect())
     }
 
-    pub fn run(&mut self, ctx: Context) -> anyhow::Result<()> {
-        let worktree = ctx.checkout_workspace()?;
-        self.pipelines = Self::parse_pipelines(".arrow")?;
-        if self.pipelines.is_empty() {
+    fn is_pipeline_file(path: &str) -> bool {
+        let path = Path::new(path);
+        let is_yaml = matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("yml") | Some("yaml")
+        );
+        let is_fragment = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.starts_with('_'),
+            None => true,
+        };
+        is_yaml && !is_fragment
+    }
+
+    pub fn parse_file(source: &dyn Source, path: &str) -> anyhow::Result<Pipeline> {
+        let content = source.read_file(path)?;
+        let value: yaml::Value = yaml::from_str(&content).map_err(|err| parse_error(path, err))?;
+        if value.get(INCLUDE_KEY).is_none() {
+            // parse from source, so that error could tell the line
+            return yaml::from_str(&content).map_err(|err| parse_error(path, err));
+        }
+        let value = Self::resolve_includes(source, path, value, 0)?;
+        yaml::from_value(value).map_err(|err| parse_error(path, err))
+    }
+
+    /// Merge included fragments into value. Fragments are merged in order,
+    /// then the including file on top, where mappings are merged deeply and
+    /// other values are overridden.
+    fn resolve_includes(
+        source: &dyn Source,
+        path: &str,
+        mut value: yaml::Value,
+        depth: usize,
+    ) -> anyhow::Result<yaml::Value> {
+        let includes = match value.as_mapping_mut() {
+            Some(mapping) => mapping.remove(INCLUDE_KEY),
+            None => None,
+        };
+        let includes: Vec<String> = match includes {
+            Some(includes) => decode::string_or_seq(includes)
+                .map_err(|err| anyhow!("{}: invalid {}: {}", path, INCLUDE_KEY, err))?,
+            None => return Ok(value),
+        };
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(anyhow!("{}: includes nested too deep", path));
+        }
+        let basedir = Path::new(path).parent().unwrap_or(Path::new(""));
+        let mut merged = yaml::Value::Mapping(yaml::Mapping::new());
+        for include in includes {
+            let include = normalize_path(&basedir.join(include));
+            let include = include.to_string_lossy();
+            let content = source
+                .read_file(&include)
+                .with_context(|| format!("Failed to include {} from {}", include, path))?;
+            let fragment: yaml::Value =
+                yaml::from_str(&content).map_err(|err| parse_error(&include, err))?;
+            let fragment = Self::resolve_includes(source, &include, fragment, depth + 1)?;
+            merge_value(&mut merged, fragment);
+        }
+        merge_value(&mut merged, value);
+        Ok(merged)
+    }
+
+    /// Run pipelines that match the context, workspace is checked out only
+    /// if any pipeline matches.
+    pub fn run(&self, ctx: &Context) -> anyhow::Result<()> {
+        if ctx.skip_requested() {
+            println!("All pipelines skipped, as requested by head commit message");
+            return Ok(());
+        }
+        self.check_forced(ctx);
+        let pipelines: Vec<&Pipeline> = self
+            .pipelines
+            .iter()
+            .filter(|pipeline| pipeline.should_run(ctx))
+            .collect();
+        if pipelines.is_empty() {
             return Ok(());
         }
+        let worktree = ctx.checkout_workspace()?;
         // make git env to all pipelines
         let envs = ctx.prepare_envs();
-        for pipeline in &self.pipelines {
-            pipeline.run(&ctx, &envs)?;
+        for pipeline in pipelines {
+            let envs = pipeline.input_envs(None)?.inherit(&envs);
+            pipeline.run(ctx, &envs)?;
         }
         drop(worktree);
         Ok(())
     }
+
+    /// Run pipeline of name on demand with params of its inputs, regardless
+    /// of `when` and `trigger`
+    pub fn trigger(
+        &self,
+        ctx: &Context,
+        name: &str,
+        params: &HashMap<String, String>,
+        dry_run: bool,
+    ) -> anyhow::Result<()> {
+        let pipeline = self
+            .pipelines
+            .iter()
+            .find(|pipeline| pipeline.name == name)
+            .ok_or_else(|| anyhow!("Pipeline {} not found on {}", name, ctx.refname))?;
+        let envs = pipeline
+            .input_envs(Some(params))?
+            .inherit(&ctx.prepare_envs());
+        if dry_run {
+            println!("Plan on {}: {}", ctx.branch_or_rev(), ctx.new_rev);
+            println!();
+            println!("{} (triggered)", pipeline.name);
+            return pipeline.plan_actions(ctx, &envs);
+        }
+        let worktree = ctx.checkout_workspace()?;
+        pipeline.run(ctx, &envs)?;
+        drop(worktree);
+        Ok(())
+    }
+
+    /// Print pipelines and actions that would run, without executing
+    /// anything nor checking out workspace.
+    pub fn plan(&self, ctx: &Context) -> anyhow::Result<()> {
+        println!("Plan on {}: {}..{}", ctx.branch, ctx.old_rev, ctx.new_rev);
+        self.check_forced(ctx);
+        let envs = ctx.prepare_envs();
+        for pipeline in &self.pipelines {
+            let envs = pipeline.input_envs(None)?.inherit(&envs);
+            pipeline.plan(ctx, &envs)?;
+        }
+        Ok(())
+    }
+
+    /// Warn of pipelines forced by commit messages that are not defined
+    fn check_forced(&self, ctx: &Context) {
+        for name in ctx.forced_pipelines() {
+            if !self.pipelines.iter().any(|pipeline| pipeline.name == name) {
+                eprintln!("Warning: pipeline {} to run is not found", name);
+            }
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+/// JSON schema of pipeline file, for editor completion. It has `include`
+/// too, which is resolved before the file is parsed as a pipeline.
+pub fn schema() -> RootSchema {
+    let mut schema = schemars::schema_for!(Pipeline);
+    let mut include = decode::string_or_seq_schema(&mut SchemaGenerator::default()).into_object();
+    include.metadata().description =
+        Some("Fragment files to merge into this one, relative to it".to_string());
+    schema
+        .schema
+        .object()
+        .properties
+        .insert(INCLUDE_KEY.to_string(), include.into());
+    schema
+}
+
+/// Build parse error that tells file and location, in `path:line:column`
+/// instead of the location yaml appends to the message
+fn parse_error(path: &str, err: yaml::Error) -> anyhow::Error {
+    match err.location() {
+        Some(loc) => {
+            let message = err.to_string();
+            let suffix = format!(" at line {} column {}", loc.line(), loc.column());
+            anyhow!(
+                "Failed to parse pipeline file {}:{}:{}: {}",
+                path,
+                loc.line(),
+                loc.column(),
+                message.strip_suffix(&suffix).unwrap_or(&message)
+            )
+        }
+        None => anyhow!("Failed to parse pipeline file {}: {}", path, err),
+    }
+}
+
+/// Merge value into base, mappings are merged deeply, others are replaced
+fn merge_value(base: &mut yaml::Value, value: yaml::Value) {
+    match (base, value) {
+        (yaml::Value::Mapping(base), yaml::Value::Mapping(value)) => {
+            for (key, value) in value {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_value(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, value) => *base = value,
+    }
+}
+
+/// A pipeline of actions, run on push when `when` matches
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Pipeline {
     name: String,
+    /// Run on all branches and changes if not specified
     #[serde(default = "WhenSpec::always")]
     when: WhenSpec,
+    /// When `notify` and `email` actions run, unless they set `on`
+    notify_on: Option<RunOn>,
+    /// Run on `push` when `when` matches, or only on demand by `arrow
+    /// trigger` if `manual`
+    #[serde(default)]
+    trigger: Trigger,
+    /// Inputs given by `arrow trigger --param`, exported as `INPUT_<NAME>`;
+    /// on push, only those with default are exported
+    #[serde(default)]
+    inputs: Inputs,
 
     #[serde(flatten)]
     envs: Envs,
 
-    actions: Vec<Action>,
+    actions: Vec<Step>,
+}
+
+/// How a pipeline is triggered
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    #[default]
+    Push,
+    Manual,
 }
 
-#[derive(Debug, Deserialize, Default)]
+/// First failed action of pipeline
+struct Failure<'a> {
+    action: &'a str,
+    /// Tail of output of the action
+    output: Vec<String>,
+    error: anyhow::Error,
+}
+
+/// Conditions for a pipeline to run
+#[derive(Debug, Deserialize, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct WhenSpec {
     #[serde(
         default = "WhenSpec::any_branch",
         deserialize_with = "decode::string_or_seq"
     )]
+    #[schemars(schema_with = "decode::string_or_seq_schema")]
     branch: Vec<String>, // list of branch to trigger on
+    /// Glob patterns of changed files to trigger on, relative to repo root
     #[serde(default)]
-    changes: Vec<String>, // list of glob patterns, relative to repo root
+    changes: GlobSet,
+    /// Glob patterns of changed files to disregard
+    #[serde(default)]
+    changes_ignore: GlobSet,
+    /// Options of `git push -o` to trigger on, any of `key=value`, or
+    /// `key` with any value
+    #[serde(default, deserialize_with = "decode::string_or_seq")]
+    #[schemars(schema_with = "decode::string_or_seq_schema")]
+    push_option: Vec<String>,
 }
 
 /// A special branch name that matches all branches
@@ -92,10 +342,21 @@ impl WhenSpec {
     pub fn always() -> WhenSpec {
         WhenSpec {
             branch: vec![STAR_BRANCH.to_string()],
-            changes: Vec::new(),
+            changes: GlobSet::default(),
+            changes_ignore: GlobSet::default(),
+            push_option: Vec::new(),
         }
     }
 
+    /// Whether any of push options is given, or none is required
+    pub fn match_push_options(&self, ctx: &Context) -> bool {
+        self.push_option.is_empty()
+            || self
+                .push_option
+                .iter()
+                .any(|option| ctx.has_push_option(option))
+    }
+
     pub fn any_branch() -> Vec<String> {
         vec!["*".to_string()]
     }
@@ -107,37 +368,470 @@ impl WhenSpec {
         if !(self.branch[0] == STAR_BRANCH || self.branch.contains(branch)) {
             return false;
         }
-        if self.changes.is_empty() {
+        if self.changes.is_empty() && self.changes_ignore.is_empty() {
             return true;
         }
 
-        // TODO: precompile glob patterns
-        self.changes.iter().any(|pat| {
-            let pattern = glob::Pattern::new(pat).unwrap();
-            match &fileset {
-                Some(fileset) => fileset.iter().any(|f| pattern.matches(f)),
-                None => false,
-            }
-        })
+        let fileset = match fileset {
+            Some(fileset) => fileset,
+            None => return false,
+        };
+        fileset
+            .iter()
+            .filter(|f| !self.changes_ignore.matches(f))
+            .any(|f| self.changes.is_empty() || self.changes.matches(f))
     }
 }
 
 impl Pipeline {
     pub fn run(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
+        println!();
+        println!("{}", self.name);
+        println!("----");
+        let envs = self.envs.inherit(parent_env).setup_output_env()?;
+        let start_time = Instant::now();
+        let mut failure: Option<Failure> = None;
+        for step in &self.actions {
+            let action = step.action();
+            if !step.run_on(self.notify_on).matches(failure.is_some()) {
+                println!();
+                println!("### {} (skipped)", action.name());
+                continue;
+            }
+            let envs = self
+                .status_envs(start_time, failure.as_ref())
+                .inherit(&envs);
+            output::clear();
+            if let Err(error) = action.run(ctx, &envs) {
+                match failure {
+                    // actions on failure go on, report the first error
+                    Some(_) => println!("  Error: {:#}", error),
+                    None => {
+                        failure = Some(Failure {
+                            action: action.name(),
+                            output: output::tail(),
+                            error,
+                        })
+                    }
+                }
+            }
+        }
+        match failure {
+            Some(failure) => Err(failure.error),
+            None => Ok(()),
+        }
+    }
+
+    /// Status of pipeline so far, exported to actions
+    fn status_envs(&self, start_time: Instant, failure: Option<&Failure>) -> Envs {
+        let status = match failure {
+            Some(_) => "failure",
+            None => "success",
+        };
+        let mut vars = HashMap::new();
+        vars.insert(PIPELINE_ENV.to_string(), self.name.clone());
+        vars.insert(STATUS_ENV.to_string(), status.to_string());
+        vars.insert(
+            DURATION_ENV.to_string(),
+            format_duration(start_time.elapsed()),
+        );
+        if let Some(failure) = failure {
+            let mut lines = failure.output.clone();
+            lines.push(format!("Error: {:#}", failure.error));
+            vars.insert(FAILED_ACTION_ENV.to_string(), failure.action.to_string());
+            vars.insert(FAILED_OUTPUT_ENV.to_string(), lines.join("\n"));
+        }
+        Envs::from_context(vars)
+    }
+
+    /// Validate pipeline definition, returns warnings if any. Env files are
+    /// looked up in root of repo, as actions run there.
+    pub fn validate(&self, root: &Path) -> anyhow::Result<Vec<String>> {
+        inputs::validate(&self.inputs)?;
+        let mut warnings = Vec::new();
+        let mut envs = vec![&self.envs];
+        envs.extend(self.actions.iter().map(|step| step.action().envs()));
+        for env_file in envs.iter().flat_map(|envs| envs.env_files()) {
+            if !root.join(env_file).is_file() {
+                warnings.push(format!("env_file {} not found", env_file));
+            }
+        }
+        Ok(warnings)
+    }
+
+    /// Print what would be done, without executing anything
+    pub fn plan(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
+        println!();
+        if self.trigger == Trigger::Manual {
+            println!("{} (skipped, manual trigger only)", self.name);
+            return Ok(());
+        }
+        if ctx.skip_requested() {
+            println!("{} (skipped, by commit message)", self.name);
+            return Ok(());
+        }
         if !self.should_run(ctx) {
+            println!("{} (skipped, when not matched)", self.name);
             return Ok(());
         }
-        println!();
-        println!("{}", self.name);
+        if self.is_forced(ctx) {
+            println!("{} (forced, by commit message)", self.name);
+        } else {
+            println!("{}", self.name);
+        }
+        self.plan_actions(ctx, parent_env)
+    }
+
+    /// Print env and actions of pipeline
+    fn plan_actions(&self, ctx: &Context, parent_env: &Envs) -> anyhow::Result<()> {
         println!("----");
         let envs = self.envs.inherit(parent_env).setup_output_env()?;
-        for action in &self.actions {
-            action.run(ctx, &envs)?;
+        print_vars(&envs.plan_env()?);
+        let envs = self.status_envs(Instant::now(), None).inherit(&envs);
+        for step in &self.actions {
+            step.action().plan(ctx, &envs)?;
+            match step.run_on(self.notify_on) {
+                RunOn::Success => {}
+                RunOn::Failure => println!("  (on failure only)"),
+                RunOn::Always => println!("  (on success or failure)"),
+            }
         }
         Ok(())
     }
 
+    /// Whether to run on push of context, `[skip arrow]` in head commit
+    /// message skips it, and `[arrow run: name]` in pushed commits forces
+    /// it regardless of `when`. Manual pipelines never run on push.
     fn should_run(&self, ctx: &Context) -> bool {
-        self.when.match_changes(&ctx.branch, ctx.get_fileset())
+        if self.trigger == Trigger::Manual || ctx.skip_requested() {
+            return false;
+        }
+        self.is_forced(ctx)
+            || (self.when.match_push_options(ctx)
+                && self.when.match_changes(&ctx.branch, ctx.get_fileset()))
+    }
+
+    /// Env of inputs resolved from params, see `inputs::resolve`
+    fn input_envs(&self, params: Option<&HashMap<String, String>>) -> anyhow::Result<Envs> {
+        let vars = inputs::resolve(&self.inputs, params)
+            .with_context(|| format!("Invalid inputs of pipeline {}", self.name))?;
+        Ok(Envs::from_context(vars))
+    }
+
+    fn is_forced(&self, ctx: &Context) -> bool {
+        ctx.forced_pipelines().contains(&self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn when(yaml: &str) -> WhenSpec {
+        yaml::from_str(yaml).unwrap()
+    }
+
+    fn files(paths: &[&str]) -> Option<Vec<String>> {
+        Some(paths.iter().map(|p| p.to_string()).collect())
+    }
+
+    /// Files in memory, by path
+    struct Files(Vec<(String, String)>);
+
+    impl Files {
+        fn new(files: &[(&str, &str)]) -> Files {
+            Files(
+                files
+                    .iter()
+                    .map(|(path, content)| (path.to_string(), content.to_string()))
+                    .collect(),
+            )
+        }
+    }
+
+    impl Source for Files {
+        fn list_files(&self, dir: &str) -> anyhow::Result<Vec<String>> {
+            let prefix = format!("{}/", dir.trim_end_matches('/'));
+            let mut files: Vec<String> = self
+                .0
+                .iter()
+                .map(|(path, _)| path.clone())
+                .filter(|path| path.starts_with(&prefix))
+                .collect();
+            files.sort();
+            Ok(files)
+        }
+
+        fn read_file(&self, path: &str) -> anyhow::Result<String> {
+            self.0
+                .iter()
+                .find(|(file, _)| file == path)
+                .map(|(_, content)| content.clone())
+                .ok_or_else(|| anyhow!("{} not found", path))
+        }
+    }
+
+    const ACTIONS: &str = "actions: [{runner: shell, name: build, script: make}]";
+
+    fn names(pipelines: &[Pipeline]) -> Vec<&str> {
+        pipelines.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    #[test]
+    fn load_pipeline_files() {
+        let app = format!("name: app\n{}", ACTIONS);
+        let lib = format!("name: lib\n{}", ACTIONS);
+        let docs = format!("name: docs\n{}", ACTIONS);
+        let source = Files::new(&[
+            (".arrow/app.yml", &app),
+            (".arrow/app.yml~", "not: [yaml"),
+            (".arrow/README.md", "# pipelines"),
+            (".arrow/_common.yml", "when: {branch: main}"),
+            (".arrow/sub/lib.yaml", &lib),
+            (".arrow/sub/deep/docs.yml", &docs),
+            (".arrow/sub/_env.yaml", "variables: {A: b}"),
+            ("other/x.yml", "not: [yaml"),
+        ]);
+        assert_eq!(
+            Pipelines::list_pipeline_files(&source, ".arrow").unwrap(),
+            vec![
+                ".arrow/app.yml",
+                ".arrow/sub/deep/docs.yml",
+                ".arrow/sub/lib.yaml"
+            ]
+        );
+        let pipelines = Pipelines::parse_pipelines(&source, ".arrow").unwrap();
+        assert_eq!(names(&pipelines), vec!["app", "docs", "lib"]);
+    }
+
+    #[test]
+    fn includes_merge_deeply() {
+        let source = Files::new(&[
+            (
+                ".arrow/app.yml",
+                "include: [_common.yml, ../shared/_notify.yml]\n\
+                 name: app\n\
+                 variables: {STAGE: prod}\n\
+                 when: {changes: 'src/**'}",
+            ),
+            (
+                ".arrow/_common.yml",
+                "include: _base.yml\n\
+                 variables: {STAGE: dev, REGION: eu}\n\
+                 when: {branch: [main]}",
+            ),
+            (".arrow/_base.yml", ACTIONS),
+            (
+                "shared/_notify.yml",
+                "variables: {CHANNEL: ops}\nnotify_on: failure",
+            ),
+        ]);
+        let pipeline = Pipelines::parse_file(&source, ".arrow/app.yml").unwrap();
+        assert_eq!(pipeline.name, "app");
+        assert_eq!(pipeline.actions.len(), 1);
+        assert!(matches!(pipeline.notify_on, Some(RunOn::Failure)));
+        assert_eq!(pipeline.when.branch, vec!["main"]);
+        assert!(pipeline.when.changes.matches("src/main.rs"));
+        let vars = pipeline.envs.build_env().unwrap();
+        assert_eq!(vars["STAGE"], "prod");
+        assert_eq!(vars["REGION"], "eu");
+        assert_eq!(vars["CHANNEL"], "ops");
+    }
+
+    #[test]
+    fn merge_values() {
+        let mut base: yaml::Value = yaml::from_str("{a: {b: 1, c: [1, 2]}, d: x}").unwrap();
+        merge_value(
+            &mut base,
+            yaml::from_str("{a: {c: [3], e: true}, d: {f: 1}}").unwrap(),
+        );
+        let expected: yaml::Value =
+            yaml::from_str("{a: {b: 1, c: [3], e: true}, d: {f: 1}}").unwrap();
+        assert_eq!(base, expected);
+    }
+
+    #[test]
+    fn include_cycle_is_too_deep() {
+        let source = Files::new(&[
+            (".arrow/app.yml", "include: _a.yml\nname: app"),
+            (".arrow/_a.yml", "include: _b.yml"),
+            (".arrow/_b.yml", "include: _a.yml"),
+        ]);
+        let err = Pipelines::parse_file(&source, ".arrow/app.yml").unwrap_err();
+        assert!(
+            err.to_string().ends_with(": includes nested too deep"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn parse_error_tells_file_and_line() {
+        let source = Files::new(&[
+            (
+                ".arrow/app.yml",
+                "name: app\nactions: []\nwhen: {brnch: main}\n",
+            ),
+            (".arrow/lib.yml", "include: _bad.yml\nname: lib"),
+            (".arrow/_bad.yml", "variables:\n  A: [b\n"),
+        ]);
+        let err = Pipelines::parse_file(&source, ".arrow/app.yml").unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.starts_with("Failed to parse pipeline file .arrow/app.yml:3:"),
+            "{}",
+            message
+        );
+        assert!(message.contains("unknown field `brnch`"), "{}", message);
+        assert!(!message.contains(" at line "), "{}", message);
+
+        let err = Pipelines::parse_file(&source, ".arrow/lib.yml").unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Failed to parse pipeline file .arrow/_bad.yml:3:"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn validate_env_files_in_root() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("env")).unwrap();
+        std::fs::write(root.path().join("env/common.env"), "A=b\n").unwrap();
+        let pipeline: Pipeline = yaml::from_str(
+            "name: app\n\
+             env_file: env/common.env\n\
+             actions: [{runner: shell, name: build, script: make, env_file: env/build.env}]",
+        )
+        .unwrap();
+        assert_eq!(
+            pipeline.validate(root.path()).unwrap(),
+            vec!["env_file env/build.env not found"]
+        );
+
+        let pipeline: Pipeline = yaml::from_str(&format!(
+            "name: app\ninputs: {{env: {{type: choice}}}}\n{}",
+            ACTIONS
+        ))
+        .unwrap();
+        let err = pipeline.validate(root.path()).unwrap_err();
+        assert_eq!(err.to_string(), "Input env of choice should have options");
+    }
+
+    #[test]
+    fn schema_of_pipeline_file() {
+        let schema = serde_json::to_value(schema()).unwrap();
+        let properties = &schema["properties"];
+        for key in ["name", "when", "actions", "inputs", "env_file", INCLUDE_KEY] {
+            assert!(properties.get(key).is_some(), "{} not in schema", key);
+        }
+        assert_eq!(properties[INCLUDE_KEY]["anyOf"][0]["type"], "string");
+        assert_eq!(properties[INCLUDE_KEY]["anyOf"][1]["type"], "array");
+        assert!(schema["required"]
+            .as_array()
+            .unwrap()
+            .contains(&"actions".into()));
+    }
+
+    /// Env set for the child process of a test, which runs the test again
+    const CHILD_ENV: &str = "ARROW_TEST_CHILD";
+
+    /// Stdout of running test of name in a child process, with `CHILD_ENV`
+    /// set, as plan prints to stdout
+    fn stdout_of_child(test: &str) -> String {
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([test, "--exact", "--nocapture", "--test-threads=1"])
+            .env(CHILD_ENV, "1")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        assert!(output.status.success(), "{}", stdout);
+        stdout
+    }
+
+    #[test]
+    fn plan_masks_secrets() {
+        if std::env::var(CHILD_ENV).is_err() {
+            let stdout = stdout_of_child("pipeline::tests::plan_masks_secrets");
+            assert!(!stdout.contains("s3cr3t"), "{}", stdout);
+            assert!(!stdout.contains("T000/B000"), "{}", stdout);
+            for line in [
+                "  BRANCH=main",
+                "  REPO_NAME=shop",
+                "  TOKEN=***",
+                "  SLACK_URL=***",
+                "  POST https://example.com/hook?key=***&branch=main",
+                "  X-Api-Key: ***",
+                r#"  {"branch":"main","token":"***"}"#,
+                "  slack ***",
+            ] {
+                assert!(stdout.lines().any(|l| l == line), "{}\n{}", line, stdout);
+            }
+            return;
+        }
+        let pipeline: Pipeline = yaml::from_str(
+            r#"
+            name: deploy
+            variables:
+              TOKEN: s3cr3t
+              SLACK_URL: https://hooks.slack.com/services/T000/B000/s3cr3t
+            actions:
+              - runner: webhook
+                name: hook
+                http:
+                  url: "https://example.com/hook?key={{TOKEN}}&branch={{BRANCH}}"
+                  headers: {X-Api-Key: "{{TOKEN}}"}
+                  body:
+                    jsonData: {token: "{{TOKEN}}", branch: "{{BRANCH}}"}
+              - runner: notify
+                name: chat
+                provider: slack
+                url: "{{SLACK_URL}}"
+            "#,
+        )
+        .unwrap();
+        let mut ctx = Context::default();
+        ctx.branch = "main".to_string();
+        ctx.repo_name = "shop".to_string();
+        ctx.old_rev = "1".repeat(40);
+        ctx.new_rev = "2".repeat(40);
+        pipeline.plan_actions(&ctx, &ctx.prepare_envs()).unwrap();
+    }
+
+    #[test]
+    fn match_changes_by_globs() {
+        let spec = when("changes: ['src/**', '!src/**/*.md']");
+        let branch = "master".to_string();
+        assert!(spec.match_changes(&branch, files(&["README.md", "src/main.rs"])));
+        assert!(!spec.match_changes(&branch, files(&["src/README.md"])));
+        assert!(!spec.match_changes(&branch, files(&[])));
+        assert!(!spec.match_changes(&branch, None));
+    }
+
+    #[test]
+    fn match_changes_ignore() {
+        let spec = when("changes_ignore: ['docs/**', '*.md']");
+        let branch = "master".to_string();
+        assert!(spec.match_changes(&branch, files(&["docs/a.md", "src/main.rs"])));
+        assert!(!spec.match_changes(&branch, files(&["docs/a.png", "sub/b.md"])));
+
+        let spec = when("{changes: 'src/**', changes_ignore: 'src/generated/**'}");
+        assert!(spec.match_changes(&branch, files(&["src/main.rs"])));
+        assert!(!spec.match_changes(&branch, files(&["src/generated/api.rs"])));
+    }
+
+    #[test]
+    fn match_changes_by_branch() {
+        let spec = when("{branch: [main, release], changes: '*.rs'}");
+        assert!(spec.match_changes(&"main".to_string(), files(&["src/main.rs"])));
+        assert!(!spec.match_changes(&"dev".to_string(), files(&["src/main.rs"])));
+        assert!(WhenSpec::always().match_changes(&"dev".to_string(), None));
+    }
+
+    #[test]
+    fn invalid_changes_pattern_is_parse_error() {
+        let err = yaml::from_str::<WhenSpec>("changes: 'src/['").unwrap_err();
+        assert!(err.to_string().contains("Invalid glob pattern"));
     }
 }
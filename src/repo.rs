@@ -1,12 +1,20 @@
 use anyhow::{anyhow, Context as _};
+use regex::Regex;
 use std::collections::HashMap;
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::OnceLock;
 
 use crate::envs::Envs;
 use crate::helper::path_to_string;
 
+/// Revision of non-existing object, e.g. old rev of new branch
+const ZERO_REV: &str = "0000000000000000000000000000000000000000";
+
+/// Object id of the empty tree, to diff the first commit of a branch against
+const EMPTY_TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
 #[derive(Debug, Default)]
 pub struct Context {
     pub refname: String, // refs/heads/master
@@ -18,8 +26,92 @@ pub struct Context {
     pub workspace: PathBuf, // where to checkout the repo
     /// whether capable of worktree or not
     cap_worktree: bool,
+    /// run in current working copy, instead of checking out workspace
+    in_place: bool,
     /// files that have changed
     fileset: Option<Vec<String>>,
+    /// commits of the push, newest first
+    commits: Vec<Commit>,
+    /// who pushed, if known by git server
+    pusher: Option<String>,
+    /// options of `git push -o`, in `key` or `key=value`
+    push_options: Vec<String>,
+}
+
+/// A commit of the push
+#[derive(Debug, Clone)]
+pub struct Commit {
+    pub rev: String,
+    pub author_name: String,
+    pub author_email: String,
+    pub committer_name: String,
+    pub committer_email: String,
+    pub subject: String,
+    /// Full message, including subject
+    pub message: String,
+}
+
+impl Commit {
+    pub fn short_rev(&self) -> &str {
+        short_rev(&self.rev)
+    }
+}
+
+/// Directive in head commit message to skip all pipelines
+const SKIP_DIRECTIVES: [&str; 2] = ["[skip arrow]", "[arrow skip]"];
+
+/// Directive of commit message to force pipelines to run, compiled once
+fn run_directive() -> &'static Regex {
+    static DIRECTIVE: OnceLock<Regex> = OnceLock::new();
+    DIRECTIVE.get_or_init(|| Regex::new(r"(?i)\[arrow run:([^\]]*)\]").unwrap())
+}
+
+/// Env key of push option key, `PUSH_OPTION_` and the key in upper case,
+/// with chars other than alphanumeric replaced by `_`
+fn push_option_env_key(key: &str) -> String {
+    let key: String = key
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+    format!("PUSH_OPTION_{}", key)
+}
+
+/// Keys of push options exported as the same env key, of which the last
+/// one wins
+fn push_option_collisions(push_options: &[String]) -> Vec<(String, String, String)> {
+    let mut keys: Vec<(String, &str)> = Vec::new();
+    let mut collisions = Vec::new();
+    for option in push_options {
+        let key = option
+            .split_once('=')
+            .map_or(option.as_str(), |(key, _)| key);
+        let env_key = push_option_env_key(key);
+        match keys.iter().find(|(seen, _)| *seen == env_key) {
+            Some((_, seen_key)) if *seen_key != key => {
+                collisions.push((seen_key.to_string(), key.to_string(), env_key))
+            }
+            Some(_) => {}
+            None => keys.push((env_key, key)),
+        }
+    }
+    collisions
+}
+
+fn warn_push_option_collisions(push_options: &[String]) {
+    for (first, second, env_key) in push_option_collisions(push_options) {
+        eprintln!(
+            "Warning: push options {} and {} are both exported as {}, the last one wins",
+            first, second, env_key
+        );
+    }
+}
+
+/// Abbreviated revision, as git shows by default
+pub fn short_rev(rev: &str) -> &str {
+    &rev[..rev.len().min(7)]
 }
 
 /// Worktree represents a checkout of repo, which will be cleaned upon drop
@@ -27,7 +119,7 @@ pub struct Worktree<'a> {
     ctx: &'a Context,
 }
 
-impl<'a> Drop for Worktree<'a> {
+impl Drop for Worktree<'_> {
     fn drop(&mut self) {
         self.ctx.cleanup_workspace().unwrap();
     }
@@ -40,14 +132,15 @@ impl Context {
         refname: String,
         old_rev: String,
         new_rev: String,
+        push_options: Vec<String>,
     ) -> anyhow::Result<Self> {
         let repo_dir = Self::resolve_repo_dir()?;
         let branch = Self::resolve_branch(&refname)?;
         let repo_name = Self::resolve_reponame(&repo_dir);
         let workspace = PathBuf::from("/tmp/arrow-workspace"); // TODO: allow to customize
-        let fileset = Self::resolve_fileset(&old_rev, &new_rev)?;
         let cap_worktree = Self::resolve_worktree_capable();
-        let ctx = Context {
+        warn_push_option_collisions(&push_options);
+        let mut ctx = Context {
             refname,
             old_rev,
             new_rev,
@@ -56,8 +149,96 @@ impl Context {
             workspace,
             repo_dir,
             cap_worktree,
-            fileset: Some(fileset),
+            in_place: false,
+            fileset: None,
+            commits: Vec::new(),
+            pusher: Self::resolve_pusher(),
+            push_options,
+        };
+        ctx.fileset = Some(ctx.resolve_fileset()?);
+        ctx.commits = ctx.resolve_commits()?;
+        Ok(ctx)
+    }
+
+    /// Resolve context of ref in repo for manual trigger, as if the head
+    /// commit of ref was pushed. Changed files are unknown, as `when` is
+    /// not checked on trigger.
+    pub fn resolve_manual(repo: &str, reference: &str) -> anyhow::Result<Self> {
+        let repo_dir = Self::git_local(&["-C", repo, "rev-parse", "--absolute-git-dir"])
+            .with_context(|| format!("Not a git repo: {}", repo))?;
+        let git = |args: &[&str]| Self::git_local(&[&["--git-dir", &repo_dir], args].concat());
+        let new_rev = git(&[
+            "rev-parse",
+            "--verify",
+            &format!("{}^{{commit}}", reference),
+        ])
+        .with_context(|| format!("Ref {} not found in {}", reference, repo))?;
+        let old_rev = git(&["rev-parse", "--verify", "-q", &format!("{}~1", new_rev)])
+            .unwrap_or_else(|_| ZERO_REV.to_string());
+        // a revision or tag is checked out detached, with no branch
+        let refname = match git(&["rev-parse", "--symbolic-full-name", reference]) {
+            Ok(name) if name == "HEAD" => git(&["symbolic-ref", "-q", "HEAD"]).ok(),
+            Ok(name) => Some(name),
+            Err(_) => None,
+        }
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "HEAD".to_string());
+        let branch = if refname.starts_with("refs/heads/") {
+            Self::resolve_branch(&refname)?
+        } else {
+            String::new()
+        };
+        let repo_dir = PathBuf::from(repo_dir);
+        let repo_name = Self::resolve_reponame(&repo_dir);
+        let mut ctx = Context {
+            refname,
+            old_rev,
+            new_rev,
+            branch,
+            repo_name,
+            workspace: PathBuf::from("/tmp/arrow-workspace"),
+            repo_dir,
+            cap_worktree: Self::resolve_worktree_capable(),
+            in_place: false,
+            fileset: None,
+            commits: Vec::new(),
+            pusher: env::var("USER").ok(),
+            push_options: Vec::new(),
+        };
+        ctx.commits = ctx.resolve_commits()?;
+        Ok(ctx)
+    }
+
+    /// Resolve context on current HEAD of working copy at current dir,
+    /// changes are those of HEAD commit.
+    pub fn resolve_local(push_options: Vec<String>) -> anyhow::Result<Self> {
+        let repo_dir = PathBuf::from(Self::git_local(&["rev-parse", "--absolute-git-dir"])?);
+        let workspace = PathBuf::from(Self::git_local(&["rev-parse", "--show-toplevel"])?);
+        let new_rev = Self::git_local(&["rev-parse", "HEAD"])?;
+        let old_rev = Self::git_local(&["rev-parse", "--verify", "-q", "HEAD~1"])
+            .unwrap_or_else(|_| ZERO_REV.to_string());
+        let refname =
+            Self::git_local(&["symbolic-ref", "-q", "HEAD"]).unwrap_or_else(|_| "HEAD".to_string());
+        let branch = Self::resolve_branch(&refname)?;
+        let repo_name = Self::resolve_reponame(&workspace);
+        warn_push_option_collisions(&push_options);
+        let mut ctx = Context {
+            refname,
+            old_rev,
+            new_rev,
+            branch,
+            repo_name,
+            workspace,
+            repo_dir,
+            cap_worktree: false,
+            in_place: true,
+            fileset: None,
+            commits: Vec::new(),
+            pusher: None,
+            push_options,
         };
+        ctx.fileset = Some(ctx.resolve_fileset()?);
+        ctx.commits = ctx.resolve_commits()?;
         Ok(ctx)
     }
 
@@ -71,46 +252,261 @@ impl Context {
             path_to_string(&self.workspace, ""),
         );
         vars.insert("GIT_DIR".to_string(), path_to_string(&self.repo_dir, ""));
-        Envs::from_vars(vars)
+        vars.insert(
+            "REV_SHORT".to_string(),
+            short_rev(&self.new_rev).to_string(),
+        );
+        vars.insert("REPO_NAME".to_string(), self.repo_name.clone());
+        vars.insert("BRANCH".to_string(), self.branch.clone());
+        vars.insert("COMMIT_COUNT".to_string(), self.commits.len().to_string());
+        let commits: Vec<String> = self
+            .commits
+            .iter()
+            .map(|commit| format!("{} {}", commit.rev, commit.subject))
+            .collect();
+        vars.insert("COMMITS".to_string(), commits.join("\n"));
+        if let Some(head) = self.commits.first() {
+            vars.insert("COMMIT_AUTHOR_NAME".to_string(), head.author_name.clone());
+            vars.insert("COMMIT_AUTHOR_EMAIL".to_string(), head.author_email.clone());
+            vars.insert("COMMITTER_NAME".to_string(), head.committer_name.clone());
+            vars.insert("COMMITTER_EMAIL".to_string(), head.committer_email.clone());
+            vars.insert("COMMIT_SUBJECT".to_string(), head.subject.clone());
+            vars.insert("COMMIT_MESSAGE".to_string(), head.message.clone());
+        }
+        if let Some(ref pusher) = self.pusher {
+            vars.insert("PUSHER".to_string(), pusher.clone());
+        }
+        if !self.push_options.is_empty() {
+            vars.insert("PUSH_OPTIONS".to_string(), self.push_options.join("\n"));
+        }
+        for option in &self.push_options {
+            let (key, value) = option.split_once('=').unwrap_or((option, "true"));
+            vars.insert(push_option_env_key(key), value.to_string());
+        }
+        Envs::from_context(vars)
+    }
+
+    /// Commits of the push, newest first
+    pub fn commits(&self) -> &[Commit] {
+        &self.commits
+    }
+
+    /// Whether head commit message asks to skip all pipelines, by
+    /// `[skip arrow]` or `[arrow skip]`
+    pub fn skip_requested(&self) -> bool {
+        match self.commits.first() {
+            Some(head) => {
+                let message = head.message.to_lowercase();
+                SKIP_DIRECTIVES
+                    .iter()
+                    .any(|directive| message.contains(directive))
+            }
+            None => false,
+        }
+    }
+
+    /// Names of pipelines forced to run by `[arrow run: name, ...]` in
+    /// messages of pushed commits
+    pub fn forced_pipelines(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for commit in &self.commits {
+            for captures in run_directive().captures_iter(&commit.message) {
+                for name in captures[1].split(',').map(str::trim) {
+                    if !name.is_empty() && !names.iter().any(|n| n == name) {
+                        names.push(name.to_string());
+                    }
+                }
+            }
+        }
+        names
     }
 
     /// Checkout or init work dir with latest changes. It will try to use
     /// worktree if possible, or fallback to clone.
     ///
-    /// It also change current working dir for the process.
-    pub fn checkout_workspace(&self) -> anyhow::Result<Worktree> {
-        if self.cap_worktree {
-            self.checkout_worktree(&self.branch)?;
+    /// It also change current working dir for the process. If context is
+    /// resolved locally, current working copy is used as is.
+    pub fn checkout_workspace(&self) -> anyhow::Result<Worktree<'_>> {
+        let workdir = if self.in_place {
+            self.workspace.clone()
+        } else if self.cap_worktree {
+            self.checkout_worktree(&self.branch)?
+        } else {
+            self.checkout_clone(&self.branch)?
+        };
+        env::set_current_dir(&workdir)?;
+        println!("Work dir: {}", workdir.display());
+        Ok(Worktree { ctx: self })
+    }
+
+    /// Branch of new revision, or short revision if checked out detached
+    pub fn branch_or_rev(&self) -> &str {
+        if self.branch.is_empty() {
+            short_rev(&self.new_rev)
+        } else {
+            &self.branch
+        }
+    }
+
+    /// Dir of working copy where actions run in
+    pub fn workdir(&self) -> PathBuf {
+        if self.in_place {
+            self.workspace.clone()
+        } else if self.cap_worktree {
+            self.build_worktree_dir(&self.branch)
+        } else {
+            self.workspace.join(&self.repo_name)
+        }
+    }
+
+    /// Whether the push deletes the ref, so there is no new revision to run
+    /// pipelines on
+    pub fn is_deletion(&self) -> bool {
+        self.new_rev == ZERO_REV
+    }
+
+    /// List files recursively under dir (relative to repo root) in the tree
+    /// of new revision, regardless of what is checked out. Nothing is listed
+    /// for a deleted ref.
+    pub fn list_files(&self, dir: &str) -> anyhow::Result<Vec<String>> {
+        if self.is_deletion() {
+            return Ok(Vec::new());
+        }
+        let dir = format!("{}/", dir.trim_end_matches('/'));
+        let output = self.git(&["ls-tree", "-r", &self.new_rev, "--", &dir])?;
+        let mut files = Vec::new();
+        // each line in format: <mode> SP <type> SP <object> TAB <file>
+        for line in output.lines() {
+            if let Some((meta, path)) = line.split_once('\t') {
+                if meta.split_whitespace().nth(1) == Some("blob") {
+                    files.push(path.to_string());
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Read content of file (relative to repo root) at new revision
+    pub fn read_file(&self, path: &str) -> anyhow::Result<String> {
+        self.git(&["show", &format!("{}:{}", self.new_rev, path)])
+            .with_context(|| format!("Failed to read {} at {}", path, self.new_rev))
+    }
+
+    /// Resolve commits of the push, newest first. For a new branch, only
+    /// the head commit is listed, as its base is unknown.
+    fn resolve_commits(&self) -> anyhow::Result<Vec<Commit>> {
+        if self.new_rev == ZERO_REV {
+            return Ok(Vec::new());
+        }
+        // fields separated by unit separator, commits by record separator
+        let format = "--format=%H%x1f%an%x1f%ae%x1f%cn%x1f%ce%x1f%s%x1f%B%x1e";
+        let range = format!("{}..{}", self.old_rev, self.new_rev);
+        let output = if self.old_rev == ZERO_REV {
+            self.git(&["log", format, "-n", "1", &self.new_rev])?
         } else {
-            self.checkout_clone(&self.branch)?;
+            self.git(&["log", format, &range])?
+        };
+        let commits = output
+            .split('\x1e')
+            .filter_map(|record| {
+                let mut fields = record.trim_start_matches('\n').split('\x1f');
+                Some(Commit {
+                    rev: fields.next().filter(|rev| !rev.is_empty())?.to_string(),
+                    author_name: fields.next()?.to_string(),
+                    author_email: fields.next()?.to_string(),
+                    committer_name: fields.next()?.to_string(),
+                    committer_email: fields.next()?.to_string(),
+                    subject: fields.next()?.to_string(),
+                    message: fields.next()?.trim_end().to_string(),
+                })
+            })
+            .collect();
+        Ok(commits)
+    }
+
+    /// Run git command against the repo, returns stdout on success
+    fn git(&self, args: &[&str]) -> anyhow::Result<String> {
+        let output = Command::new("git")
+            .arg("--git-dir")
+            .arg(&self.repo_dir)
+            .args(args)
+            .stderr(Stdio::piped())
+            .output()
+            .with_context(|| format!("Command error: git {}", args.join(" ")))?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
         }
-        Result::Ok(Worktree { ctx: &self })
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    /// Run git command in current dir, returns trimmed stdout on success
+    fn git_local(args: &[&str]) -> anyhow::Result<String> {
+        let output = Command::new("git")
+            .args(args)
+            .stderr(Stdio::inherit())
+            .output()
+            .with_context(|| format!("Command error: git {}", args.join(" ")))?;
+        if !output.status.success() {
+            return Err(anyhow!("git {} failed", args.join(" ")));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Ensure working copy at workdir is checked out at new revision
+    fn verify_checkout(&self, workdir: &Path) -> anyhow::Result<()> {
+        let output = Command::new("git")
+            .current_dir(workdir)
+            .env_remove("GIT_DIR")
+            .arg("rev-parse")
+            .arg("HEAD")
+            .output()
+            .with_context(|| format!("Failed to resolve HEAD of {}", workdir.display()))?;
+        let head = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !output.status.success() || head != self.new_rev {
+            return Err(anyhow!(
+                "Work dir {} is at '{}', expect {}",
+                workdir.display(),
+                head,
+                self.new_rev
+            ));
+        }
+        Ok(())
     }
 
     /// Cleanup work dir after all actions are done
     pub fn cleanup_workspace(&self) -> anyhow::Result<()> {
+        if self.in_place {
+            return Ok(());
+        }
+        // change back to repo dir
+        env::set_current_dir(&self.repo_dir)?;
         if self.cap_worktree {
-            self.cleanup_worktree(&self.branch)
-        } else {
-            self.cleanup_clone(&self.branch)
+            self.remove_worktree(&self.branch)?;
         }
+        // probably should remove the workdir of clone?
+        Ok(())
     }
 
     fn print_git_ref(&self) {
         println!("GIT_DIR: {}", self.repo_dir.display());
         println!(
             "On {}: {}..{}",
-            self.branch,
+            self.branch_or_rev(),
             &self.old_rev[..8],
             &self.new_rev[..8]
         );
     }
 
-    /// Checkout by clone the repo to {workspace}/{repo-name}
-    fn checkout_clone(&self, branch: &String) -> anyhow::Result<()> {
+    /// Checkout by clone the repo to {workspace}/{repo-name}, detached if
+    /// there is no branch. Returns the work dir, current dir is not changed
+    fn checkout_clone(&self, branch: &str) -> anyhow::Result<PathBuf> {
         self.print_git_ref();
         let workdir = self.workspace.join(&self.repo_name);
-        let _ = std::fs::create_dir_all(&workdir)?;
+        std::fs::create_dir_all(&workdir)?;
         let script = format!(
             "
             if [ ! -d .git ]; then
@@ -119,14 +515,16 @@ impl Context {
             fi
             git clean -fdx
             git remote update
-            git checkout {branch}
-            git reset --hard {new_rev}
+            git checkout {branch} {new_rev}
             ",
             origin = self.repo_dir.display(),
-            branch = branch,
+            branch = match branch {
+                "" => "--detach".to_string(),
+                branch => format!("-B {}", branch),
+            },
             new_rev = self.new_rev
         );
-        let _ = Command::new("sh")
+        let status = Command::new("sh")
             .current_dir(&workdir)
             .env_remove("GIT_DIR") // working in new repo now
             .arg("-ex")
@@ -134,54 +532,63 @@ impl Context {
             .arg(&script)
             .stdout(Stdio::inherit())
             .stderr(Stdio::inherit())
-            .output()
+            .status()
             .with_context(|| {
                 format!(
                     "Failed to checkout and update work dir at {}",
                     workdir.display()
                 )
             })?;
-        env::set_current_dir(&workdir)?;
-        println!("Work dir: {}", workdir.display());
-        Ok(())
-    }
-
-    fn cleanup_clone(&self, _: &String) -> anyhow::Result<()> {
-        // change back to repo dir
-        env::set_current_dir(&self.repo_dir)?;
-        // probably should remove the workdir?
-        Ok(())
+        if !status.success() {
+            return Err(anyhow!(
+                "Failed to checkout and update work dir at {}: {}",
+                workdir.display(),
+                status
+            ));
+        }
+        self.verify_checkout(&workdir)?;
+        Ok(workdir)
     }
 
-    /// Use git worktree to checkout a working copy at {workspace}/app-{branch}
-    fn checkout_worktree(&self, branch: &String) -> anyhow::Result<()> {
+    /// Use git worktree to checkout a working copy of new revision at
+    /// {workspace}/app-{branch}, or app-{short rev} if detached. Returns the
+    /// work dir, current dir is not changed
+    fn checkout_worktree(&self, branch: &str) -> anyhow::Result<PathBuf> {
         self.print_git_ref();
-        let workdir = Self::build_worktree_dir(&self, branch);
-        let script = format!("git worktree add {} {}", workdir.to_string_lossy(), branch);
+        let workdir = self.build_worktree_dir(branch);
+        let script = format!(
+            "git worktree add --force --detach {} {}",
+            workdir.to_string_lossy(),
+            self.new_rev
+        );
 
-        let _ = Command::new("sh")
+        let status = Command::new("sh")
             .current_dir(&self.repo_dir)
             .arg("-ex")
             .arg("-c")
             .arg(&script)
             .stdout(Stdio::inherit())
             .stderr(Stdio::inherit())
-            .output()
+            .status()
             .with_context(|| {
                 format!(
                     "Failed to checkout and update work tree at {}",
                     workdir.display()
                 )
             })?;
-        env::set_current_dir(&workdir)?;
-        println!("Work dir: {}", env::current_dir()?.display());
-        Ok(())
+        if !status.success() {
+            return Err(anyhow!(
+                "Failed to checkout and update work tree at {}: {}",
+                workdir.display(),
+                status
+            ));
+        }
+        self.verify_checkout(&workdir)?;
+        Ok(workdir)
     }
 
-    fn cleanup_worktree(&self, branch: &String) -> anyhow::Result<()> {
-        // change back to repo dir
-        env::set_current_dir(&self.repo_dir)?;
-        let workdir = Self::build_worktree_dir(&self, branch);
+    fn remove_worktree(&self, branch: &str) -> anyhow::Result<()> {
+        let workdir = self.build_worktree_dir(branch);
         let script = format!("git worktree remove --force {}", workdir.to_string_lossy());
 
         let _ = Command::new("sh")
@@ -196,62 +603,80 @@ impl Context {
         Ok(())
     }
 
-    fn build_worktree_dir(&self, branch: &String) -> PathBuf {
+    fn build_worktree_dir(&self, branch: &str) -> PathBuf {
         let mut worktree = self.workspace.clone();
-        let name = format!("{}-{}", self.repo_name, branch);
+        let name = match branch {
+            "" => format!("{}-{}", self.repo_name, short_rev(&self.new_rev)),
+            branch => format!("{}-{}", self.repo_name, branch),
+        };
         worktree.push(name);
         worktree
     }
 
-    fn resolve_branch(refname: &String) -> anyhow::Result<String> {
-        match refname.split("/").last() {
-            Some(branch) => return Ok(branch.to_string()),
-            None => return Err(anyhow!("No branch resolved from refname '{}'", refname)),
-        };
+    fn resolve_branch(refname: &str) -> anyhow::Result<String> {
+        match refname.rsplit('/').next() {
+            Some(branch) => Ok(branch.to_string()),
+            None => Err(anyhow!("No branch resolved from refname '{}'", refname)),
+        }
+    }
+
+    /// Who pushed, as told by git server, e.g. `GL_USERNAME` of GitLab, or
+    /// `REMOTE_USER` of http backend
+    fn resolve_pusher() -> Option<String> {
+        ["GL_USERNAME", "REMOTE_USER"]
+            .iter()
+            .filter_map(|key| env::var(key).ok())
+            .find(|value| !value.is_empty())
     }
 
     fn resolve_repo_dir() -> anyhow::Result<PathBuf> {
         match env::var("GIT_DIR") {
-            Ok(dir) => return Ok(std::fs::canonicalize(PathBuf::from(dir))?),
-            Err(_) => {
-                return Err(anyhow!(
-                    "env GIT_DIR not found, it should be run from bare repo"
-                ))
-            }
-        };
+            Ok(dir) => Ok(std::fs::canonicalize(PathBuf::from(dir))?),
+            Err(_) => Err(anyhow!(
+                "env GIT_DIR not found, it should be run from bare repo"
+            )),
+        }
     }
 
-    fn resolve_reponame(repodir: &PathBuf) -> String {
+    fn resolve_reponame(repodir: &Path) -> String {
         let name = match repodir.file_stem() {
             Some(name) => name.to_str().unwrap().to_string(),
             None => return String::from("Unamed-repo"),
         };
         if name == ".git" {
             let parentdir = repodir.parent().unwrap().to_path_buf();
-            return Self::resolve_reponame(&parentdir);
+            Self::resolve_reponame(&parentdir)
         } else {
-            return name;
+            name
         }
     }
 
+    /// Whether option is given by `git push -o`, `key=value` matches the
+    /// option exactly, and `key` matches the key with any value
+    pub fn has_push_option(&self, option: &str) -> bool {
+        self.push_options.iter().any(|given| {
+            given == option
+                || (!option.contains('=')
+                    && given.split_once('=').is_some_and(|(key, _)| key == option))
+        })
+    }
+
     pub fn get_fileset(&self) -> Option<Vec<String>> {
-        return self.fileset.clone();
+        self.fileset.clone()
     }
 
-    fn resolve_fileset(old_rev: &String, new_rev: &String) -> anyhow::Result<Vec<String>> {
-        let mut fileset: Vec<String> = Vec::new();
-        let diff_cmd = format!("git diff --name-only {}..{}", old_rev, new_rev);
-        let output = Command::new("sh")
-            .arg("-c")
-            .arg(&diff_cmd)
-            .stderr(Stdio::inherit())
-            .output()
-            .with_context(|| format!("Command error: {}", &diff_cmd))?;
-        let output = String::from_utf8_lossy(&output.stdout);
-        for line in output.lines() {
-            fileset.push(line.to_string());
+    /// Files changed by the push. For a new branch, all files of new
+    /// revision are taken as changed, by diff against the empty tree.
+    fn resolve_fileset(&self) -> anyhow::Result<Vec<String>> {
+        if self.is_deletion() {
+            return Ok(Vec::new());
         }
-        Ok(fileset)
+        let old_rev = match self.old_rev.as_str() {
+            ZERO_REV => EMPTY_TREE,
+            rev => rev,
+        };
+        let output = self.git(&["diff", "--name-only", old_rev, &self.new_rev])?;
+        Ok(output.lines().map(String::from).collect())
     }
 
     /// resole if git is capable of worktree
@@ -260,6 +685,331 @@ impl Context {
         if let Ok(output) = ret {
             return output.status.success();
         }
-        return false;
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Run git in dir, returns trimmed stdout
+    fn git_in(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .current_dir(dir)
+            .env_remove("GIT_DIR")
+            .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    /// Repo with a commit of a pipeline file, returns the repo and head rev
+    fn init_repo() -> (TempDir, String) {
+        let dir = TempDir::new().unwrap();
+        git_in(dir.path(), &["init", "-q"]);
+        std::fs::create_dir_all(dir.path().join(".arrow/deploy")).unwrap();
+        std::fs::write(dir.path().join(".arrow/deploy/app.yml"), "name: app\n").unwrap();
+        std::fs::write(dir.path().join("README.md"), "readme\n").unwrap();
+        git_in(dir.path(), &["add", "-A"]);
+        git_in(dir.path(), &["commit", "-q", "-m", "init"]);
+        let head = git_in(dir.path(), &["rev-parse", "HEAD"]);
+        (dir, head)
+    }
+
+    fn context(dir: &TempDir, old_rev: &str, new_rev: &str) -> Context {
+        Context {
+            refname: "refs/heads/master".to_string(),
+            old_rev: old_rev.to_string(),
+            new_rev: new_rev.to_string(),
+            repo_dir: dir.path().join(".git"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn list_files_at_new_rev() {
+        let (dir, head) = init_repo();
+        let ctx = context(&dir, ZERO_REV, &head);
+        assert!(!ctx.is_deletion());
+        assert_eq!(
+            ctx.list_files(".arrow").unwrap(),
+            vec![".arrow/deploy/app.yml"]
+        );
+        assert_eq!(
+            ctx.read_file(".arrow/deploy/app.yml").unwrap(),
+            "name: app\n"
+        );
+    }
+
+    /// Commit a file of content on top of HEAD, returns new head rev
+    fn commit_file(dir: &TempDir, path: &str, content: &str) -> String {
+        std::fs::write(dir.path().join(path), content).unwrap();
+        git_in(dir.path(), &["add", "-A"]);
+        git_in(
+            dir.path(),
+            &["commit", "-q", "-m", &format!("update {}", path)],
+        );
+        git_in(dir.path(), &["rev-parse", "HEAD"])
+    }
+
+    #[test]
+    fn fileset_of_push() {
+        let (dir, base) = init_repo();
+        let head = commit_file(&dir, "README.md", "updated\n");
+        let ctx = context(&dir, &base, &head);
+        assert_eq!(ctx.resolve_fileset().unwrap(), vec!["README.md"]);
+    }
+
+    #[test]
+    fn fileset_of_new_branch_is_all_files() {
+        let (dir, head) = init_repo();
+        let ctx = context(&dir, ZERO_REV, &head);
+        assert_eq!(
+            ctx.resolve_fileset().unwrap(),
+            vec![".arrow/deploy/app.yml", "README.md"]
+        );
+    }
+
+    #[test]
+    fn deletion_push_lists_no_files() {
+        let (dir, head) = init_repo();
+        let ctx = context(&dir, &head, ZERO_REV);
+        assert!(ctx.is_deletion());
+        assert!(ctx.list_files(".arrow").unwrap().is_empty());
+        assert!(ctx.resolve_commits().unwrap().is_empty());
+        assert!(ctx.resolve_fileset().unwrap().is_empty());
+    }
+
+    /// Context of pushed commits of messages, newest first
+    #[test]
+    fn commits_of_push_as_env() {
+        let (dir, base) = init_repo();
+        let first = commit_file(&dir, "README.md", "updated\n");
+        git_in(
+            dir.path(),
+            &[
+                "commit",
+                "-q",
+                "--allow-empty",
+                "--author",
+                "Jane Doe <jane@example.com>",
+                "-m",
+                "Fix checkout",
+                "-m",
+                "Detached heads are cloned\nwith --detach.",
+            ],
+        );
+        let head = git_in(dir.path(), &["rev-parse", "HEAD"]);
+        let mut ctx = context(&dir, &base, &head);
+        ctx.branch = "master".to_string();
+        ctx.commits = ctx.resolve_commits().unwrap();
+
+        let revs: Vec<&str> = ctx.commits().iter().map(|c| c.rev.as_str()).collect();
+        assert_eq!(revs, vec![head.as_str(), first.as_str()]);
+        let vars = ctx.prepare_envs().build_env().unwrap();
+        assert_eq!(vars["COMMIT_COUNT"], "2");
+        assert_eq!(
+            vars["COMMITS"],
+            format!("{} Fix checkout\n{} update README.md", head, first)
+        );
+        assert_eq!(vars["REV_SHORT"], &head[..7]);
+        assert_eq!(vars["REV_OLD"], base);
+        assert_eq!(vars["REV_NEW"], head);
+        assert_eq!(vars["COMMIT_SUBJECT"], "Fix checkout");
+        assert_eq!(
+            vars["COMMIT_MESSAGE"],
+            "Fix checkout\n\nDetached heads are cloned\nwith --detach."
+        );
+        assert_eq!(vars["COMMIT_AUTHOR_NAME"], "Jane Doe");
+        assert_eq!(vars["COMMIT_AUTHOR_EMAIL"], "jane@example.com");
+        assert_eq!(vars["COMMITTER_NAME"], "Test");
+        assert_eq!(vars["COMMITTER_EMAIL"], "test@example.com");
+
+        // new branch only has the head commit
+        let ctx = context(&dir, ZERO_REV, &head);
+        let commits = ctx.resolve_commits().unwrap();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].subject, "Fix checkout");
+    }
+
+    fn context_of_messages(messages: &[&str]) -> Context {
+        let commits = messages
+            .iter()
+            .map(|message| Commit {
+                rev: ZERO_REV.to_string(),
+                author_name: String::new(),
+                author_email: String::new(),
+                committer_name: String::new(),
+                committer_email: String::new(),
+                subject: message.lines().next().unwrap_or_default().to_string(),
+                message: message.to_string(),
+            })
+            .collect();
+        Context {
+            commits,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn skip_requested_by_head_commit() {
+        for message in [
+            "fix typo [skip arrow]",
+            "fix typo\n\n[Arrow Skip]",
+            "[SKIP ARROW] docs",
+        ] {
+            assert!(
+                context_of_messages(&[message, "init"]).skip_requested(),
+                "{}",
+                message
+            );
+        }
+        assert!(!context_of_messages(&["fix typo", "[skip arrow]"]).skip_requested());
+        assert!(!context_of_messages(&["[skip ci]"]).skip_requested());
+        assert!(!context_of_messages(&[]).skip_requested());
+    }
+
+    #[test]
+    fn forced_pipelines_of_commits() {
+        let ctx = context_of_messages(&[
+            "release\n\n[arrow run: deploy, notify ]",
+            "[ARROW RUN:docs][arrow run: deploy,,]",
+            "[arrow run:]",
+            "[arrow run deploy]",
+        ]);
+        assert_eq!(ctx.forced_pipelines(), vec!["deploy", "notify", "docs"]);
+        assert!(context_of_messages(&["init"]).forced_pipelines().is_empty());
+    }
+
+    fn context_of_push_options(push_options: &[&str]) -> Context {
+        Context {
+            push_options: push_options.iter().map(|o| o.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn has_push_option_by_key_or_pair() {
+        let ctx = context_of_push_options(&["ci.skip", "env=staging", "tag=a=b"]);
+        assert!(ctx.has_push_option("ci.skip"));
+        assert!(ctx.has_push_option("env"));
+        assert!(ctx.has_push_option("env=staging"));
+        assert!(ctx.has_push_option("tag=a=b"));
+        assert!(ctx.has_push_option("tag"));
+        assert!(!ctx.has_push_option("env=prod"));
+        assert!(!ctx.has_push_option("ci.skip=true"));
+        assert!(!ctx.has_push_option("ci"));
+        assert!(!ctx.has_push_option("staging"));
+    }
+
+    #[test]
+    fn push_options_as_env() {
+        let ctx = context_of_push_options(&["ci.skip", "deploy-to=eu-1", "Mode=a=b"]);
+        let vars = ctx.prepare_envs().build_env().unwrap();
+        assert_eq!(vars["PUSH_OPTIONS"], "ci.skip\ndeploy-to=eu-1\nMode=a=b");
+        assert_eq!(vars["PUSH_OPTION_CI_SKIP"], "true");
+        assert_eq!(vars["PUSH_OPTION_DEPLOY_TO"], "eu-1");
+        assert_eq!(vars["PUSH_OPTION_MODE"], "a=b");
+
+        let vars = context_of_push_options(&[])
+            .prepare_envs()
+            .build_env()
+            .unwrap();
+        assert!(!vars.keys().any(|key| key.starts_with("PUSH_OPTION")));
+    }
+
+    #[test]
+    fn colliding_push_options() {
+        let options =
+            |options: &[&str]| -> Vec<String> { options.iter().map(|o| o.to_string()).collect() };
+        assert_eq!(
+            push_option_collisions(&options(&["a-b=1", "env=x", "a.b=2", "A_B"])),
+            vec![
+                (
+                    "a-b".to_string(),
+                    "a.b".to_string(),
+                    "PUSH_OPTION_A_B".to_string()
+                ),
+                (
+                    "a-b".to_string(),
+                    "A_B".to_string(),
+                    "PUSH_OPTION_A_B".to_string()
+                ),
+            ]
+        );
+        assert!(push_option_collisions(&options(&["env=a", "env=b", "env"])).is_empty());
+        let vars = context_of_push_options(&["a-b=1", "a.b=2"])
+            .prepare_envs()
+            .build_env()
+            .unwrap();
+        assert_eq!(vars["PUSH_OPTION_A_B"], "2");
+    }
+
+    #[test]
+    fn manual_context_of_branch_tag_or_revision() {
+        let (dir, base) = init_repo();
+        let head = commit_file(&dir, "README.md", "updated\n");
+        git_in(dir.path(), &["tag", "v1", &base]);
+        let repo = dir.path().to_str().unwrap();
+        let branch = git_in(dir.path(), &["symbolic-ref", "--short", "HEAD"]);
+
+        for reference in ["HEAD", branch.as_str()] {
+            let ctx = Context::resolve_manual(repo, reference).unwrap();
+            assert_eq!(ctx.refname, format!("refs/heads/{}", branch));
+            assert_eq!(ctx.branch, branch);
+            assert_eq!(
+                (ctx.old_rev.as_str(), ctx.new_rev.as_str()),
+                (&*base, &*head)
+            );
+        }
+
+        for (reference, refname) in [("v1", "refs/tags/v1"), (&base[..10], "HEAD")] {
+            let ctx = Context::resolve_manual(repo, reference).unwrap();
+            assert_eq!(ctx.refname, refname);
+            assert_eq!(ctx.branch, "");
+            assert_eq!(ctx.new_rev, base);
+            assert_eq!(ctx.old_rev, ZERO_REV);
+            assert_eq!(ctx.branch_or_rev(), short_rev(&base));
+            let vars = ctx.prepare_envs().build_env().unwrap();
+            assert_eq!(vars["BRANCH"], "");
+        }
+
+        let err = Context::resolve_manual(repo, "nope").unwrap_err();
+        assert!(err.to_string().starts_with("Ref nope not found in"));
+    }
+
+    #[test]
+    fn checkout_detached_revision() {
+        let (dir, base) = init_repo();
+        commit_file(&dir, "README.md", "updated\n");
+        let workspace = TempDir::new().unwrap();
+        let mut ctx = Context::resolve_manual(dir.path().to_str().unwrap(), &base).unwrap();
+        ctx.workspace = workspace.path().to_path_buf();
+        ctx.repo_name = "app".to_string();
+
+        let cwd = env::current_dir().unwrap();
+        ctx.cap_worktree = true;
+        let worktree_dir = ctx.checkout_worktree(&ctx.branch).unwrap();
+        assert_eq!(worktree_dir, ctx.workdir());
+        assert_eq!(
+            worktree_dir,
+            workspace.path().join(format!("app-{}", short_rev(&base)))
+        );
+        assert_eq!(git_in(&worktree_dir, &["rev-parse", "HEAD"]), base);
+        ctx.remove_worktree(&ctx.branch).unwrap();
+        assert!(!worktree_dir.exists());
+
+        ctx.cap_worktree = false;
+        let clone_dir = ctx.checkout_clone(&ctx.branch).unwrap();
+        assert_eq!(clone_dir, ctx.workdir());
+        assert_eq!(env::current_dir().unwrap(), cwd);
+        assert_eq!(git_in(&clone_dir, &["rev-parse", "HEAD"]), base);
+        let branches = git_in(&clone_dir, &["branch", "--list"]);
+        assert!(branches.starts_with("* (HEAD detached at"), "{}", branches);
+        assert!(git_in(&clone_dir, &["branch", "--list", &base]).is_empty());
     }
 }
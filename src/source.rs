@@ -0,0 +1,59 @@
+use anyhow::Context as _;
+use std::path::{Path, PathBuf};
+
+use crate::repo::Context;
+
+/// Source where pipeline definitions are read from
+pub trait Source {
+    /// List files recursively under dir, or the file itself if it is a file
+    fn list_files(&self, dir: &str) -> anyhow::Result<Vec<String>>;
+    /// Read content of file
+    fn read_file(&self, path: &str) -> anyhow::Result<String>;
+}
+
+/// Pipeline definitions in the tree of pushed revision
+impl Source for Context {
+    fn list_files(&self, dir: &str) -> anyhow::Result<Vec<String>> {
+        Context::list_files(self, dir)
+    }
+
+    fn read_file(&self, path: &str) -> anyhow::Result<String> {
+        Context::read_file(self, path)
+    }
+}
+
+/// Pipeline definitions on local file system
+pub struct LocalSource;
+
+impl Source for LocalSource {
+    fn list_files(&self, dir: &str) -> anyhow::Result<Vec<String>> {
+        let mut files = Vec::new();
+        let path = PathBuf::from(dir);
+        if path.is_file() {
+            files.push(dir.to_string());
+        } else if path.is_dir() {
+            walk_dir(&path, &mut files)?;
+        }
+        Ok(files)
+    }
+
+    fn read_file(&self, path: &str) -> anyhow::Result<String> {
+        std::fs::read_to_string(path).with_context(|| format!("Failed to open file {}", path))
+    }
+}
+
+fn walk_dir(dir: &Path, files: &mut Vec<String>) -> anyhow::Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read dir {}", dir.display()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.path());
+    for entry in entries {
+        let path = entry.path();
+        if path.is_dir() {
+            walk_dir(&path, files)?;
+        } else if path.is_file() {
+            files.push(path.to_string_lossy().to_string());
+        }
+    }
+    Ok(())
+}